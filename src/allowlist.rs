@@ -0,0 +1,933 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of ledgeracio.
+//
+// ledgeracio is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ledgeracio is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ledgeracio.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Ledgeracio allowlist parser/signer
+//!
+//! This compiles textual allowlists into the signed binary format accepted by
+//! the Ledgeracio app, and decodes and verifies signed allowlists.  See
+//! FORMATS.md for both formats.
+
+use ed25519_dalek::{ExpandedSecretKey, PublicKey};
+use std::{collections::{hash_map::Entry, BTreeMap, HashMap},
+          convert::TryFrom,
+          io::{prelude::*, Error, ErrorKind}};
+use substrate_subxt::sp_core::crypto::{AccountId32 as AccountId, Ss58AddressFormat, Ss58Codec};
+
+/// Options controlling how a textual allowlist is compiled
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Options {
+    /// Drop duplicate addresses instead of rejecting them
+    pub dedup: bool,
+    /// Sort addresses by account ID, so that the output depends only on the
+    /// set of addresses and not on their order
+    pub sort: bool,
+    /// The maximum number of addresses, counted after duplicates are dropped
+    pub limit: Option<usize>,
+}
+
+/// Labels for addresses, taken from trailing `;` comments.  These are never
+/// signed.
+pub type Annotations = BTreeMap<String, String>;
+
+/// Compile the textual allowlist read from `reader` and sign it with `sk`.
+/// The output depends only on the input, the key, the nonce, and `options`.
+///
+/// Each address is either an SS58 address for `network`, or a raw account ID
+/// written as `0x` followed by 64 hexadecimal digits.  The network of a raw
+/// account ID cannot be checked, so a warning is logged for each one.  Either
+/// way, the address is stored in SS58 form.
+///
+/// ```
+/// use ed25519_dalek::{ExpandedSecretKey, PublicKey, SecretKey};
+/// use ledgeracio::{allowlist, Ss58AddressFormat};
+///
+/// let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+/// let public = PublicKey::from(&secret);
+/// let signed = allowlist::parse::<_, ledgeracio::AccountId>(
+///     &b"5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y\n"[..],
+///     Ss58AddressFormat::SubstrateAccount,
+///     &public,
+///     &ExpandedSecretKey::from(&secret),
+///     5,
+///     allowlist::Options::default(),
+/// )
+/// .unwrap();
+/// assert_eq!(signed.len(), 72 + 64);
+/// assert_eq!(signed[..8], [5, 0, 0, 0, 1, 0, 0, 0]);
+/// assert_eq!(
+///     hex::encode(&signed[8..72]),
+///     "1b1b4bea6209196b8976837d95100dbfd7eec8d3b0536d472e493c22f3650c98\
+///      0649085d28a8e747565597d1486b478b215ea16b367d2ad9aed75303c72a3406"
+/// );
+/// assert_eq!(&signed[72..120], &b"5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y"[..]);
+/// assert_eq!(signed[120..], [0; 16]);
+/// ```
+///
+/// # Errors
+///
+/// Fails if reading fails, if a line is not a valid address for `network`, if
+/// an address is duplicated (unless `options.dedup` is set), or if there are
+/// more addresses than `options.limit`.
+pub fn parse<T: BufRead, U: Ss58Codec>(
+    reader: T,
+    network: Ss58AddressFormat,
+    pk: &PublicKey,
+    sk: &ExpandedSecretKey,
+    nonce: u32,
+    options: Options,
+) -> std::io::Result<Vec<u8>> {
+    parse_annotated::<T, U>(reader, network, pk, sk, nonce, options).map(|(v, _)| v)
+}
+
+/// Like [`parse`], but also returns the labels given by trailing `;` comments,
+/// such as `5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y ; operator`.
+///
+/// # Errors
+///
+/// Fails in the same cases as [`parse`].
+pub fn parse_annotated<T: BufRead, U: Ss58Codec>(
+    reader: T,
+    network: Ss58AddressFormat,
+    pk: &PublicKey,
+    sk: &ExpandedSecretKey,
+    nonce: u32,
+    options: Options,
+) -> std::io::Result<(Vec<u8>, Annotations)> {
+    let mut seen = HashMap::new();
+    let mut entries = vec![];
+    let mut annotations = Annotations::new();
+    for (l, i) in (1_usize..).zip(reader.lines()) {
+        let i = i?;
+        let trimmed = i.trim_start().trim_end();
+        if trimmed.starts_with(';') || trimmed.starts_with('#') || trimmed.is_empty() {
+            continue
+        }
+        let (address, label) = parse_line(l, trimmed, network)?;
+        // Compare decoded account IDs, so that differently-written forms of the
+        // same address are also caught.
+        match seen.entry(address.clone()) {
+            Entry::Occupied(_) if options.dedup => continue,
+            Entry::Occupied(first) => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "duplicate address on line {}: already present on line {}",
+                        l,
+                        first.get()
+                    ),
+                ))
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(l);
+            }
+        }
+        match label {
+            Some(label) if !label.is_empty() => {
+                annotations.insert(address.to_ss58check_with_version(network), label.to_owned());
+            }
+            _ => {}
+        }
+        entries.push(address);
+    }
+    match options.limit {
+        Some(limit) if entries.len() > limit => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "allowlist has {} addresses, but the limit is {}",
+                    entries.len(),
+                    limit
+                ),
+            ))
+        }
+        _ => {}
+    }
+    if options.sort {
+        entries.sort_unstable();
+    }
+    Ok((compile(&entries, network, pk, sk, nonce)?, annotations))
+}
+
+/// Parse a line of a textual allowlist that is not blank and not a comment,
+/// returning the account ID and the label, if any.  `l` is the line number.
+fn parse_line(
+    l: usize,
+    trimmed: &str,
+    network: Ss58AddressFormat,
+) -> std::io::Result<(AccountId, Option<&str>)> {
+    let (trimmed, label) = match trimmed.find(';') {
+        Some(index) => (
+            trimmed[..index].trim_end(),
+            Some(trimmed[index + 1..].trim()),
+        ),
+        None => (trimmed, None),
+    };
+    let address: AccountId = if let Some(hex) = trimmed.strip_prefix("0x") {
+        // Raw account IDs carry no network, so there is nothing to check
+        let account = <[u8; 32]>::try_from(&*hex::decode(hex).unwrap_or_default())
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "parse error on line {}: {} is not a 32-byte hex account ID",
+                        l, trimmed
+                    ),
+                )
+            })?;
+        log::warn!(
+            "line {}: {} is a hex account ID, so its network cannot be checked",
+            l,
+            trimmed
+        );
+        account.into()
+    } else {
+        let (address, address_type) = crate::parse_address(trimmed).map_err(|i| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("parse error on line {}: {}", l, i),
+            )
+        })?;
+        crate::validate_network(trimmed, address_type, network).map_err(|i| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid network on line {}: {}", l, i),
+            )
+        })?;
+        address
+    };
+    Ok((address, label))
+}
+
+/// A line of a textual allowlist, as returned by [`read_text`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TextLine {
+    /// A comment, including its leading `;` or `#`
+    Comment(String),
+    /// An address, with its label, if any
+    Address(AccountId, Option<String>),
+}
+
+/// Read a textual allowlist without compiling it.  Blank lines are dropped,
+/// and addresses are checked in the same way as by [`parse`].  Duplicates are
+/// kept.
+///
+/// # Errors
+///
+/// Fails if reading fails or if a line is not a valid address for `network`.
+pub fn read_text<T: BufRead>(
+    reader: T,
+    network: Ss58AddressFormat,
+) -> std::io::Result<Vec<TextLine>> {
+    let mut lines = vec![];
+    for (l, i) in (1_usize..).zip(reader.lines()) {
+        let i = i?;
+        let trimmed = i.trim_start().trim_end();
+        if trimmed.starts_with(';') || trimmed.starts_with('#') {
+            lines.push(TextLine::Comment(trimmed.to_owned()))
+        } else if !trimmed.is_empty() {
+            let (address, label) = parse_line(l, trimmed, network)?;
+            let label = label.filter(|label| !label.is_empty()).map(str::to_owned);
+            lines.push(TextLine::Address(address, label))
+        }
+    }
+    Ok(lines)
+}
+
+/// Compile `addresses`, in order, into a binary allowlist for `network` and
+/// sign it with `sk`.  [`parse`] uses this, so both produce the same bytes for
+/// the same addresses in the same order.
+///
+/// # Errors
+///
+/// Fails if there are more than `2**32 - 1` addresses.
+pub fn compile(
+    addresses: &[AccountId],
+    network: Ss58AddressFormat,
+    pk: &PublicKey,
+    sk: &ExpandedSecretKey,
+    nonce: u32,
+) -> std::io::Result<Vec<u8>> {
+    let mut v = vec![0; 72];
+    v[..4].copy_from_slice(&nonce.to_le_bytes());
+    for address in addresses {
+        let address = address.to_ss58check_with_version(network);
+        let bytes = address.as_bytes();
+        let current_len = v.len();
+        v.extend_from_slice(&[0_u8; 64]);
+        v[current_len..current_len + bytes.len()].copy_from_slice(bytes);
+    }
+    let total_len_bytes = u32::try_from((v.len() - 68) >> 6)
+        .map_err(|_| Error::new(ErrorKind::Other, "Data too long".to_owned()))?
+        .to_le_bytes();
+    v[4..8].copy_from_slice(&total_len_bytes);
+    let digest = blake2b_simd::Params::new()
+        .hash_length(32)
+        .to_state()
+        .update(&v[..8])
+        .update(&v[72..])
+        .finalize();
+    let signature = sk.sign(&digest.as_bytes(), &pk);
+    v[8..72].copy_from_slice(&signature.to_bytes()[..]);
+    Ok(v)
+}
+
+/// A decoded binary allowlist.  Its signature has not necessarily been
+/// verified.
+pub struct Allowlist {
+    /// The nonce
+    pub nonce: u32,
+    /// The addresses, in the order they are stored
+    pub addresses: Vec<String>,
+    account_ids: Vec<AccountId>,
+    signature: [u8; 64],
+    digest: blake2b_simd::Hash,
+}
+
+/// The error wrapped by the [`std::io::Error`] returned when an allowlist
+/// signature does not verify
+#[derive(::thiserror::Error, Debug)]
+#[error("Allowlist forged!")]
+pub struct Forged;
+
+impl Allowlist {
+    /// Verify the signature of this allowlist
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`Forged`] if the signature is not valid for `pk`.
+    pub fn verify(&self, pk: &PublicKey) -> std::io::Result<()> {
+        ed25519_dalek::PublicKey::verify_strict(
+            &pk,
+            self.digest.as_bytes(),
+            &ed25519_dalek::Signature::new(self.signature),
+        )
+        .map_err(|_| Error::new(ErrorKind::InvalidData, Forged))
+    }
+
+    /// Check whether this allowlist was signed by `pk`.  This is [`verify`],
+    /// for callers that do not need the error.
+    ///
+    /// [`verify`]: Allowlist::verify
+    pub fn is_signed_by(&self, pk: &PublicKey) -> bool { self.verify(pk).is_ok() }
+
+    /// The account IDs of the addresses, in the same order
+    pub fn account_ids(&self) -> &[AccountId] { &self.account_ids }
+}
+
+/// Decode a binary allowlist without verifying its signature.  The structure
+/// of the file is fully checked, and all addresses must be valid for
+/// `network`.  To decode an allowlist that is already in memory, pass it as a
+/// `&[u8]`.
+///
+/// The allowlist does not say which key signed it; check the signature with
+/// [`Allowlist::verify`] or [`Allowlist::is_signed_by`].
+///
+/// # Errors
+///
+/// Fails if reading fails, if the file is truncated or has trailing data, if
+/// an address is not padded with NUL bytes, or if an address is not valid for
+/// `network`.
+pub fn decode<T: BufRead>(reader: T, network: Ss58AddressFormat) -> std::io::Result<Allowlist> {
+    decode_for(reader, Some(network)).map(|(allowlist, _)| allowlist)
+}
+
+/// Check the structure of a binary allowlist without knowing its network or
+/// its signing key.  All addresses must be valid and for the same network,
+/// which is returned along with the decoded allowlist.  The network is `None`
+/// if the allowlist is empty.
+///
+/// # Errors
+///
+/// Fails in the same cases as [`decode`], where the network is that of the
+/// first address.
+pub fn validate<T: BufRead>(reader: T) -> std::io::Result<(Allowlist, Option<Ss58AddressFormat>)> {
+    decode_for(reader, None)
+}
+
+fn truncated(what: String) -> impl FnOnce(Error) -> Error {
+    move |e| {
+        if e.kind() == ErrorKind::UnexpectedEof {
+            Error::new(ErrorKind::InvalidData, format!("file truncated: {}", what))
+        } else {
+            e
+        }
+    }
+}
+
+fn decode_for<T: BufRead>(
+    mut reader: T,
+    mut network: Option<Ss58AddressFormat>,
+) -> std::io::Result<(Allowlist, Option<Ss58AddressFormat>)> {
+    let mut addresses = vec![];
+    let mut account_ids = vec![];
+    let mut nonce = [0_u8; 4];
+    let mut length = [0_u8; 4];
+    let mut signature = [0_u8; 64];
+    let header = || "too short for the 72-byte header".to_owned();
+    reader.read_exact(&mut nonce[..]).map_err(truncated(header()))?;
+    reader.read_exact(&mut length[..]).map_err(truncated(header()))?;
+    let mut digest = blake2b_simd::Params::new().hash_length(32).to_state();
+    digest.update(&nonce);
+    digest.update(&length);
+    let length = u32::from_le_bytes(length);
+    reader.read_exact(&mut signature[..]).map_err(truncated(header()))?;
+    for i in 0..length {
+        let mut address = [0_u8; 65];
+        reader.read_exact(&mut address[..64]).map_err(truncated(format!(
+            "the header says there are {} addresses, but only {} are present",
+            length, i
+        )))?;
+        digest.update(&address[..64]);
+        assert_eq!(address[64], b'\0');
+        let len = address
+            .iter()
+            .position(|&s| s == b'\0')
+            .expect("our string is NUL-terminated");
+        if address[len..].iter().any(|&s| s != b'\0') {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("address {} is not padded with NUL bytes", i),
+            ))
+        }
+        let trimmed = core::str::from_utf8(&address[..len]).map_err(|j| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid UTF8 in address {}: {}", i, j),
+            )
+        })?;
+        let (account_id, address_type) =
+            crate::parse_address::<AccountId>(trimmed).map_err(|j| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("parse error on line {}: {}", i, j),
+                )
+            })?;
+        let expected = *network.get_or_insert_with(|| {
+            Ss58AddressFormat::try_from(address_type)
+                .unwrap_or_else(|()| Ss58AddressFormat::Custom(address_type))
+        });
+        crate::validate_network(trimmed, address_type, expected).map_err(|j| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid network on line {}: {}", i, j),
+            )
+        })?;
+        addresses.push(trimmed.to_owned());
+        account_ids.push(account_id)
+    }
+    let mut dummy = [0_u8; 1];
+    if reader.read(&mut dummy)? != 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "junk at end of file".to_owned(),
+        ))
+    }
+    let allowlist = Allowlist {
+        nonce: u32::from_le_bytes(nonce),
+        addresses,
+        account_ids,
+        signature,
+        digest: digest.finalize(),
+    };
+    Ok((allowlist, network))
+}
+
+/// Decode a binary allowlist and verify its signature, returning the nonce and
+/// the addresses.
+///
+/// # Errors
+///
+/// Fails in the same cases as [`decode`], or if the signature is not valid for
+/// `pk`.
+pub fn inspect<T: BufRead, U: Ss58Codec>(
+    reader: T,
+    network: Ss58AddressFormat,
+    pk: &PublicKey,
+) -> std::io::Result<(u32, Vec<String>)> {
+    let allowlist = decode(reader, network)?;
+    allowlist.verify(pk)?;
+    Ok((allowlist.nonce, allowlist.addresses))
+}
+
+/// Write a verified allowlist in the textual format.  The nonce, the name of
+/// the network, and the fingerprint of the signing key are written as
+/// comments, so signing the output again with the same key and nonce
+/// reproduces the original file.
+///
+/// # Errors
+///
+/// Fails if writing fails.
+pub fn write_text<W: Write>(
+    mut output: W,
+    nonce: u32,
+    network: &str,
+    fingerprint: &str,
+    addresses: &[String],
+    annotations: &Annotations,
+) -> std::io::Result<()> {
+    writeln!(output, "; nonce {}", nonce)?;
+    writeln!(output, "; network {}", network)?;
+    writeln!(output, "; signed by key {}", fingerprint)?;
+    for i in addresses {
+        match annotations.get(i) {
+            Some(label) => writeln!(output, "{} ; {}", i, label)?,
+            None => writeln!(output, "{}", i)?,
+        }
+    }
+    Ok(())
+}
+
+/// Read the name of the network from the `; network` comment written by
+/// [`write_text`].  Only the comments and blank lines before the first
+/// address are searched.
+///
+/// # Errors
+///
+/// Fails if reading fails.
+pub fn text_network<T: BufRead>(reader: T) -> std::io::Result<Option<String>> {
+    for line in reader.lines() {
+        let line = line?;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue
+        }
+        match trimmed.strip_prefix(';') {
+            Some(comment) => match comment.trim_start().strip_prefix("network ") {
+                Some(name) => return Ok(Some(name.trim().to_owned())),
+                None => continue,
+            },
+            None => break,
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::Keypair;
+    const BUF: &[u8] = br#"
+5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y
+; a comment
+
+# another comment
+   ; leading spaces
+   5GQvjFcJBCGTFeb2hvtQ9yRfbDNQajLJbW1yzgCra5uUTLvn
+5Cw8KtiVsBx4AK9SCzAMmXvprJiYuhRYwDUA4WHJ55ghYgYL
+5EhBPkiqA1rkoFZL6o87bSpgfTptHzp6nE3VkH4dRUed1Qdh
+5G3uDdTW8MeGW1QZR9FeZuN1exiVJZnUJ9ovyJexubiytNUj     
+5FbtadyFPdDZMiLYjdEwAyFqavVwzYueEYX8Z6fsL4UrxTXx
+5ENTEF2sAtM89XxdwRxwSKDF7hxX9udy7zdr2G4i8bRdbBH9
+5EWgCx3UMqzYt9vSf7GCHd2jhRUYF7GqVNeyPjpXxGkLV7b4
+5DFxRkcYqWa1CFkqKzM7meytTKyPMR72TPJjBb6S5zvnpuCz
+		"#;
+    const NONCE: u32 = 0;
+    #[test]
+    fn accepts_own_output() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng {});
+        let parsed: Vec<u8> = parse::<&[u8], AccountId>(
+            &mut BUF,
+            Ss58AddressFormat::SubstrateAccount,
+            &keypair.public,
+            &(&keypair.secret).into(),
+            NONCE,
+            Options::default(),
+        )
+        .expect("no error");
+        let (nonce, inspected) = inspect::<&[u8], AccountId>(
+            &mut &*parsed,
+            Ss58AddressFormat::SubstrateAccount,
+            &keypair.public,
+        )
+        .expect("no error");
+        assert_eq!(nonce, NONCE);
+        assert_eq!(
+            inspected,
+            &[
+                "5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y",
+                "5GQvjFcJBCGTFeb2hvtQ9yRfbDNQajLJbW1yzgCra5uUTLvn",
+                "5Cw8KtiVsBx4AK9SCzAMmXvprJiYuhRYwDUA4WHJ55ghYgYL",
+                "5EhBPkiqA1rkoFZL6o87bSpgfTptHzp6nE3VkH4dRUed1Qdh",
+                "5G3uDdTW8MeGW1QZR9FeZuN1exiVJZnUJ9ovyJexubiytNUj",
+                "5FbtadyFPdDZMiLYjdEwAyFqavVwzYueEYX8Z6fsL4UrxTXx",
+                "5ENTEF2sAtM89XxdwRxwSKDF7hxX9udy7zdr2G4i8bRdbBH9",
+                "5EWgCx3UMqzYt9vSf7GCHd2jhRUYF7GqVNeyPjpXxGkLV7b4",
+                "5DFxRkcYqWa1CFkqKzM7meytTKyPMR72TPJjBb6S5zvnpuCz"
+            ][..]
+        );
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "called `Result::unwrap()` on an `Err` value: Custom { kind: InvalidData, \
+                    error: \"invalid network on line 2: Network mismatch: address \
+                    5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y is for network substrate \
+                    (prefix 42), but you asked to use network polkadot (prefix 0)\" }"
+    )]
+    fn rejects_bad_file() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng {});
+        parse::<&[u8], AccountId>(
+            &mut BUF,
+            Ss58AddressFormat::PolkadotAccount,
+            &keypair.public,
+            &(&keypair.secret).into(),
+            NONCE,
+            Options::default(),
+        )
+        .unwrap();
+    }
+
+    const DUPLICATES: &[u8] = b"5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y
+5GQvjFcJBCGTFeb2hvtQ9yRfbDNQajLJbW1yzgCra5uUTLvn
+   5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y
+";
+
+    fn compile_text(list: &[u8], keypair: &Keypair, options: Options) -> std::io::Result<Vec<u8>> {
+        parse::<&[u8], AccountId>(
+            list,
+            Ss58AddressFormat::SubstrateAccount,
+            &keypair.public,
+            &(&keypair.secret).into(),
+            NONCE,
+            options,
+        )
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng {});
+        let exact = [BUF, &b"5DFxRkcYqWa1CFkqKzM7meytTKyPMR72TPJjBb6S5zvnpuCz\n"[..]].concat();
+        assert_eq!(
+            compile_text(&exact, &keypair, Options::default())
+                .unwrap_err()
+                .to_string(),
+            "duplicate address on line 15: already present on line 14"
+        );
+        assert_eq!(
+            compile_text(DUPLICATES, &keypair, Options::default())
+                .unwrap_err()
+                .to_string(),
+            "duplicate address on line 3: already present on line 1"
+        );
+    }
+
+    #[test]
+    fn names_both_networks() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng {});
+        let list = b"DgTsAzGmukx3E6NjKELKzSquZKtKr2FeCh8x5CZC6Q9zdcg
+1279MBuU1L1Vj7HSvFUHaBuzcb3JDUmDGKasihuxGPDBS2pa
+";
+        assert_eq!(
+            parse::<&[u8], AccountId>(
+                &list[..],
+                Ss58AddressFormat::KusamaAccount,
+                &keypair.public,
+                &(&keypair.secret).into(),
+                NONCE,
+                Options::default(),
+            )
+            .unwrap_err()
+            .to_string(),
+            "invalid network on line 2: Network mismatch: address \
+             1279MBuU1L1Vj7HSvFUHaBuzcb3JDUmDGKasihuxGPDBS2pa is for network polkadot (prefix \
+             0), but you asked to use network kusama (prefix 2)"
+        );
+    }
+
+    #[test]
+    fn dedup_drops_duplicates() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng {});
+        let options = Options {
+            dedup: true,
+            ..Options::default()
+        };
+        let parsed = compile_text(DUPLICATES, &keypair, options).unwrap();
+        let (_, inspected) = inspect::<&[u8], AccountId>(
+            &mut &*parsed,
+            Ss58AddressFormat::SubstrateAccount,
+            &keypair.public,
+        )
+        .unwrap();
+        assert_eq!(
+            inspected,
+            &[
+                "5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y",
+                "5GQvjFcJBCGTFeb2hvtQ9yRfbDNQajLJbW1yzgCra5uUTLvn"
+            ][..]
+        );
+    }
+
+    #[test]
+    fn sorting_ignores_order() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng {});
+        let options = Options {
+            sort: true,
+            ..Options::default()
+        };
+        let reversed: Vec<u8> = BUF
+            .split(|&c| c == b'\n')
+            .rev()
+            .collect::<Vec<_>>()
+            .join(&b'\n');
+        assert_eq!(
+            compile_text(BUF, &keypair, options).unwrap(),
+            compile_text(&reversed, &keypair, options).unwrap()
+        );
+        assert_ne!(
+            compile_text(BUF, &keypair, Options::default()).unwrap(),
+            compile_text(&reversed, &keypair, Options::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn limit_counts_deduplicated_addresses() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng {});
+        let options = Options {
+            dedup: true,
+            limit: Some(1),
+            ..Options::default()
+        };
+        assert_eq!(
+            compile_text(DUPLICATES, &keypair, options)
+                .unwrap_err()
+                .to_string(),
+            "allowlist has 2 addresses, but the limit is 1"
+        );
+        let options = Options {
+            limit: Some(2),
+            ..options
+        };
+        compile_text(DUPLICATES, &keypair, options).unwrap();
+    }
+
+    #[test]
+    fn annotations_are_not_signed() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng {});
+        let annotated = b"5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y ; first operator
+5GQvjFcJBCGTFeb2hvtQ9yRfbDNQajLJbW1yzgCra5uUTLvn;
+   5Cw8KtiVsBx4AK9SCzAMmXvprJiYuhRYwDUA4WHJ55ghYgYL;second
+";
+        let plain = b"5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y
+5GQvjFcJBCGTFeb2hvtQ9yRfbDNQajLJbW1yzgCra5uUTLvn
+5Cw8KtiVsBx4AK9SCzAMmXvprJiYuhRYwDUA4WHJ55ghYgYL
+";
+        let (signed, annotations) = parse_annotated::<&[u8], AccountId>(
+            &annotated[..],
+            Ss58AddressFormat::SubstrateAccount,
+            &keypair.public,
+            &(&keypair.secret).into(),
+            NONCE,
+            Options::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            signed,
+            compile_text(&plain[..], &keypair, Options::default()).unwrap()
+        );
+        let expected: Annotations = vec![
+            (
+                "5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y".to_owned(),
+                "first operator".to_owned(),
+            ),
+            (
+                "5Cw8KtiVsBx4AK9SCzAMmXvprJiYuhRYwDUA4WHJ55ghYgYL".to_owned(),
+                "second".to_owned(),
+            ),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(annotations, expected);
+    }
+
+    #[test]
+    fn inspect_output_round_trips() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng {});
+        let nonce = 17;
+        let (signed, annotations) = parse_annotated::<&[u8], AccountId>(
+            b"5GQvjFcJBCGTFeb2hvtQ9yRfbDNQajLJbW1yzgCra5uUTLvn ; label\n\
+              5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y\n",
+            Ss58AddressFormat::SubstrateAccount,
+            &keypair.public,
+            &(&keypair.secret).into(),
+            nonce,
+            Options::default(),
+        )
+        .unwrap();
+        let (inspected_nonce, addresses) = inspect::<&[u8], AccountId>(
+            &*signed,
+            Ss58AddressFormat::SubstrateAccount,
+            &keypair.public,
+        )
+        .unwrap();
+        let mut text = vec![];
+        write_text(
+            &mut text,
+            inspected_nonce,
+            "Westend",
+            "00:00:00:00:00:00:00:00",
+            &addresses,
+            &annotations,
+        )
+        .unwrap();
+        assert!(text.starts_with(b"; nonce 17\n; network Westend\n"));
+        assert_eq!(text_network(&*text).unwrap().as_deref(), Some("Westend"));
+        let resigned = parse::<&[u8], AccountId>(
+            &*text,
+            Ss58AddressFormat::SubstrateAccount,
+            &keypair.public,
+            &(&keypair.secret).into(),
+            inspected_nonce,
+            Options::default(),
+        )
+        .unwrap();
+        assert_eq!(resigned, signed);
+    }
+
+    #[test]
+    fn read_text_keeps_comments_labels_and_duplicates() {
+        let text = b"; team\n\n5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y ; operator\n\
+                     5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y ;\n";
+        let lines = read_text(&text[..], Ss58AddressFormat::SubstrateAccount).unwrap();
+        let account: AccountId =
+            crate::parse_address("5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y")
+                .unwrap()
+                .0;
+        assert_eq!(lines, vec![
+            TextLine::Comment("; team".to_owned()),
+            TextLine::Address(account.clone(), Some("operator".to_owned())),
+            TextLine::Address(account, None),
+        ]);
+        assert!(read_text(
+            &b"5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y\n"[..],
+            Ss58AddressFormat::KusamaAccount
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn text_network_only_reads_the_header() {
+        assert_eq!(text_network(&b""[..]).unwrap(), None);
+        assert_eq!(
+            text_network(&b"# list\n\n;network Kusama \n"[..]).unwrap().as_deref(),
+            Some("Kusama")
+        );
+        let late = b"5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y\n; network Kusama\n";
+        assert_eq!(text_network(&late[..]).unwrap(), None);
+    }
+
+    #[test]
+    fn compile_matches_parse() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng {});
+        let addresses: Vec<AccountId> = BUF
+            .split(|&c| c == b'\n')
+            .map(|line| core::str::from_utf8(line).unwrap().trim())
+            .filter(|line| line.starts_with('5'))
+            .map(|line| crate::parse_address(line).unwrap().0)
+            .collect();
+        assert_eq!(addresses.len(), 9);
+        assert_eq!(
+            compile(
+                &addresses,
+                Ss58AddressFormat::SubstrateAccount,
+                &keypair.public,
+                &(&keypair.secret).into(),
+                NONCE,
+            )
+            .unwrap(),
+            compile_text(BUF, &keypair, Options::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn decode_round_trips() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng {});
+        let other = Keypair::generate(&mut rand::rngs::OsRng {});
+        let secret = (&keypair.secret).into();
+        for network in &[
+            Ss58AddressFormat::PolkadotAccount,
+            Ss58AddressFormat::KusamaAccount,
+            Ss58AddressFormat::SubstrateAccount,
+        ] {
+            for count in 0..20_u8 {
+                let nonce = u32::from(count) * 0x0101_0101;
+                let account_ids: Vec<AccountId> = (0..count)
+                    .map(|i| AccountId::from([i.wrapping_mul(37); 32]))
+                    .collect();
+                let signed =
+                    compile(&account_ids, *network, &keypair.public, &secret, nonce).unwrap();
+                let allowlist = decode(&signed[..], *network).unwrap();
+                assert_eq!(allowlist.nonce, nonce);
+                assert_eq!(allowlist.account_ids(), &account_ids[..]);
+                let addresses: Vec<String> = account_ids
+                    .iter()
+                    .map(|id| id.to_ss58check_with_version(*network))
+                    .collect();
+                assert_eq!(allowlist.addresses, addresses);
+                assert!(allowlist.is_signed_by(&keypair.public));
+                assert!(!allowlist.is_signed_by(&other.public));
+            }
+        }
+    }
+
+    #[test]
+    fn accepts_hex_account_ids() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng {});
+        let hex = b"0x30ebced819769b85028e9f05ddfeef05b4f59a27785d228782b3115758204a7a\n";
+        assert_eq!(
+            compile_text(hex, &keypair, Options::default()).unwrap(),
+            compile_text(
+                b"5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y\n",
+                &keypair,
+                Options::default()
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            compile_text(b"0x30ebced8\n", &keypair, Options::default())
+                .unwrap_err()
+                .to_string(),
+            "parse error on line 1: 0x30ebced8 is not a 32-byte hex account ID"
+        );
+        let both = [&hex[..], &b"5DArCreQ9Yk2HaGvxcRHS35qky3eXBD5BprPZQvbiJBfFY6Y\n"[..]].concat();
+        assert_eq!(
+            compile_text(&both, &keypair, Options::default())
+                .unwrap_err()
+                .to_string(),
+            "duplicate address on line 2: already present on line 1"
+        );
+    }
+
+    #[test]
+    fn validate_checks_structure() {
+        let keypair = Keypair::generate(&mut rand::rngs::OsRng {});
+        let signed = compile_text(BUF, &keypair, Options::default()).unwrap();
+        let (allowlist, network) = validate(&*signed).unwrap();
+        assert_eq!(allowlist.addresses.len(), 9);
+        assert_eq!(network, Some(Ss58AddressFormat::SubstrateAccount));
+        let error = |signed: &[u8]| validate(signed).err().unwrap().to_string();
+        assert_eq!(error(&signed[..40]), "file truncated: too short for the 72-byte header");
+        assert_eq!(
+            error(&signed[..72 + 64 * 3 + 10]),
+            "file truncated: the header says there are 9 addresses, but only 3 are present"
+        );
+        let mut padded = signed.clone();
+        padded[72 + 63] = b'x';
+        assert_eq!(error(&padded), "address 0 is not padded with NUL bytes");
+        let mut junk = signed;
+        junk.push(0);
+        assert_eq!(error(&junk), "junk at end of file");
+    }
+}
@@ -16,29 +16,133 @@
 
 //! Routines for parsing public and secret keys
 
-use super::{KEY_MAGIC, KEY_VERSION};
-use ed25519_dalek::{ExpandedSecretKey, Keypair, PublicKey};
+use super::{ENCRYPTED_KEY_VERSION, KEY_MAGIC, KEY_VERSION};
+use chacha20poly1305::{aead::{Aead, NewAead, Payload},
+                       Key, XChaCha20Poly1305, XNonce};
+use ed25519_dalek::{ExpandedSecretKey, Keypair, PublicKey, SecretKey};
 use ledgeracio::Error;
+use rand::{rngs::OsRng, RngCore};
 use regex::bytes::Regex;
+use scrypt::ScryptParams;
+use sha2::{Digest, Sha256};
 use std::{convert::{TryFrom, TryInto},
           str};
 use substrate_subxt::sp_core::crypto::Ss58AddressFormat;
+use zeroize::Zeroizing;
 
-/// Parse a Ledgeracio secret key file
-pub(crate) fn parse_secret(secret: &[u8], network: Ss58AddressFormat) -> Result<Keypair, Error> {
-    if secret.len() != 88 {
-        return Err(format!("Ledgeracio secret keys are 88 bytes, not {}", secret.len()).into())
+/// The length of an unencrypted secret key file
+const SECRET_KEY_LEN: usize = 88;
+
+/// The length of an encrypted secret key file
+const ENCRYPTED_SECRET_KEY_LEN: usize = 160;
+
+/// Derive the key used to encrypt a secret key from a passphrase
+fn derive_key(passphrase: &str, salt: &[u8]) -> Result<Zeroizing<[u8; 32]>, Error> {
+    let params = ScryptParams::new(15, 8, 1).map_err(|_| "invalid scrypt parameters".to_owned())?;
+    let mut key = Zeroizing::new([0_u8; 32]);
+    scrypt::scrypt(passphrase.as_bytes(), salt, &params, &mut *key)
+        .map_err(|_| "scrypt failed".to_owned())?;
+    Ok(key)
+}
+
+/// Serialize a Ledgeracio secret key file.  If `passphrase` is provided, the
+/// secret key is encrypted with it.  The buffer is wiped when dropped.
+///
+/// See FORMATS.md for the format of this file.
+pub(crate) fn secret_key_file(
+    keypair: &Keypair,
+    network: Ss58AddressFormat,
+    passphrase: Option<&str>,
+) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let public = keypair.public.to_bytes();
+    // Allocate enough up front that the secret is never copied by a reallocation
+    let mut file = Zeroizing::new(Vec::with_capacity(ENCRYPTED_SECRET_KEY_LEN));
+    file.extend_from_slice(KEY_MAGIC);
+    match passphrase {
+        None => {
+            file.extend_from_slice(&u16::from(KEY_VERSION).to_le_bytes());
+            file.push(network.into());
+            file.extend_from_slice(keypair.secret.as_bytes());
+        }
+        Some(passphrase) => {
+            file.extend_from_slice(&u16::from(ENCRYPTED_KEY_VERSION).to_le_bytes());
+            file.push(network.into());
+            let (mut salt, mut nonce) = ([0_u8; 32], [0_u8; 24]);
+            let mut rng = OsRng {};
+            rng.fill_bytes(&mut salt);
+            rng.fill_bytes(&mut nonce);
+            let key = derive_key(passphrase, &salt)?;
+            let aad = [&file[..], &public[..]].concat();
+            let ciphertext = XChaCha20Poly1305::new(Key::from_slice(&*key))
+                .encrypt(XNonce::from_slice(&nonce), Payload {
+                    msg: keypair.secret.as_bytes(),
+                    aad: &aad,
+                })
+                .map_err(|_| "encryption failed".to_owned())?;
+            file.extend_from_slice(&salt);
+            file.extend_from_slice(&nonce);
+            file.extend_from_slice(&ciphertext);
+        }
     }
-    if &secret[..21] != KEY_MAGIC {
-        return Err("Not a Ledgeracio secret key ― wrong magic number"
-            .to_owned()
-            .into())
+    file.extend_from_slice(&public);
+    Ok(file)
+}
+
+/// The network stored in a Ledgeracio secret key file, if it has a valid
+/// header.  The rest of the file is not checked.
+pub(crate) fn secret_network(secret: &[u8]) -> Option<Ss58AddressFormat> {
+    if !secret.starts_with(KEY_MAGIC) {
+        return None
     }
-    if secret[21..23] != [KEY_VERSION, 0][..] {
+    let prefix = *secret.get(23)?;
+    Some(Ss58AddressFormat::try_from(prefix).unwrap_or_else(|()| Ss58AddressFormat::Custom(prefix)))
+}
+
+/// Parse a Ledgeracio secret key file.  If the key is encrypted, `passphrase`
+/// is called to obtain the passphrase.
+pub(crate) fn parse_secret<F: FnOnce() -> Result<String, Error>>(
+    secret: &[u8],
+    network: Ss58AddressFormat,
+    passphrase: F,
+) -> Result<Keypair, Error> {
+    const HEADER_LEN: usize = 24;
+    if secret.len() < HEADER_LEN && KEY_MAGIC.starts_with(&secret[..secret.len().min(21)]) {
         return Err(format!(
-            "Expected a version {} secret key, but got version {}",
-            KEY_VERSION,
-            u16::from_le_bytes(secret[21..23].try_into().unwrap())
+            "Truncated key file (expected at least {} bytes, got {})",
+            HEADER_LEN,
+            secret.len()
+        )
+        .into())
+    }
+    if !secret.starts_with(KEY_MAGIC) {
+        return Err(if secret.starts_with(b"untrusted comment: ") {
+            "Not a Ledgeracio secret key (bad magic).  This looks like a public key file."
+        } else {
+            "Not a Ledgeracio secret key (bad magic)"
+        }
+        .to_owned()
+        .into())
+    }
+    let version = u16::from_le_bytes(secret[21..23].try_into().unwrap());
+    let (encrypted, expected_len) = if version == u16::from(KEY_VERSION) {
+        (false, SECRET_KEY_LEN)
+    } else if version == u16::from(ENCRYPTED_KEY_VERSION) {
+        (true, ENCRYPTED_SECRET_KEY_LEN)
+    } else {
+        return Err(format!("Unsupported key version {}", version).into())
+    };
+    if secret.len() < expected_len {
+        return Err(format!(
+            "Truncated key file (expected {} bytes, got {})",
+            expected_len,
+            secret.len()
+        )
+        .into())
+    } else if secret.len() > expected_len {
+        return Err(format!(
+            "Key file too long (expected {} bytes, got {})",
+            expected_len,
+            secret.len()
         )
         .into())
     }
@@ -55,21 +159,91 @@ pub(crate) fn parse_secret(secret: &[u8], network: Ss58AddressFormat) -> Result<
         .into())
     }
 
-    let keypair = Keypair::from_bytes(&secret[24..88])?;
-    let secret_expanded = ExpandedSecretKey::from(&keypair.secret);
-    if PublicKey::from(&secret_expanded) != PublicKey::from_bytes(&secret[56..88])? {
-        return Err("Public and secret keys don’t match".to_owned().into())
+    let public = PublicKey::from_bytes(&secret[secret.len() - 32..])?;
+    let secret_key = if encrypted {
+        let key = derive_key(&Zeroizing::new(passphrase()?), &secret[24..56])?;
+        let aad = [&secret[..24], &public.as_bytes()[..]].concat();
+        let plaintext = XChaCha20Poly1305::new(Key::from_slice(&*key))
+            .decrypt(XNonce::from_slice(&secret[56..80]), Payload {
+                msg: &secret[80..128],
+                aad: &aad,
+            })
+            .map_err(|_| crate::status::crypto("Wrong passphrase, or corrupt secret key"))?;
+        SecretKey::from_bytes(&Zeroizing::new(plaintext))?
+    } else {
+        SecretKey::from_bytes(&secret[24..56])?
+    };
+    let secret_expanded = ExpandedSecretKey::from(&secret_key);
+    if PublicKey::from(&secret_expanded) != public {
+        return Err(crate::status::crypto("Public and secret keys don’t match"))
     }
-    Ok(keypair)
+    Ok(Keypair {
+        secret: secret_key,
+        public,
+    })
 }
 
-/// Parse a Ledgeracio public key
+/// A short fingerprint of a public key, for comparison by eye: the first 8
+/// bytes of its SHA-256 hash, in hexadecimal and separated by colons
+pub(crate) fn fingerprint(key: &PublicKey) -> String {
+    Sha256::digest(key.as_bytes())[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The human-readable network name used in public key headers
+///
+/// Westend uses the generic Substrate prefix (42), so that prefix is written as
+/// “Westend”.  Networks without a name are written as their numeric SS58
+/// prefix.
+pub(crate) fn network_name(network: Ss58AddressFormat) -> String {
+    match network {
+        Ss58AddressFormat::KusamaAccount => "Kusama".to_owned(),
+        Ss58AddressFormat::PolkadotAccount => "Polkadot".to_owned(),
+        Ss58AddressFormat::SubstrateAccount => "Westend".to_owned(),
+        other => u8::from(other).to_string(),
+    }
+}
+
+/// Parse a network name, as written by [`network_name`]
+pub(crate) fn parse_network_name(name: &str) -> Result<Ss58AddressFormat, Error> {
+    if let Ok(prefix) = name.parse::<u8>() {
+        return Ok(Ss58AddressFormat::try_from(prefix)
+            .unwrap_or_else(|()| Ss58AddressFormat::Custom(prefix)))
+    }
+    match &*name.to_ascii_lowercase() {
+        "westend" => Ok(Ss58AddressFormat::SubstrateAccount),
+        lowercase => Ss58AddressFormat::try_from(lowercase)
+            .map_err(|()| format!("invalid network {}", name).into()),
+    }
+}
+
+/// Serialize a Ledgeracio public key, including its fingerprint
 ///
 /// See FORMATS.md for the format of this key.
-pub(crate) fn parse_public(unparsed: &[u8]) -> Result<(PublicKey, Ss58AddressFormat), Error> {
-    let re = Regex::new(
-        r"^untrusted comment: Ledgeracio v2 network ([[:alpha:]]+) public key\n([[:alnum:]/+]+)\n$",
+pub(crate) fn public_key_file(key: &PublicKey, network: Ss58AddressFormat) -> String {
+    let mut data = b"Edaaaaaaaa"[..].to_owned();
+    data.extend_from_slice(key.as_bytes());
+    format!(
+        "untrusted comment: Ledgeracio v2 network {} public key\n{}\nfingerprint: {}\n",
+        network_name(network),
+        base64::encode(&data),
+        fingerprint(key)
     )
+}
+
+/// Parse a Ledgeracio public key.  If the file has a fingerprint line, the key
+/// must match it.
+///
+/// See FORMATS.md for the format of this key.
+pub(crate) fn parse_public(unparsed: &[u8]) -> Result<(PublicKey, Ss58AddressFormat), Error> {
+    let re = Regex::new(concat!(
+        r"^untrusted comment: Ledgeracio v2 network ([[:alnum:]]+) public key\n",
+        r"([[:alnum:]/+]+)\n",
+        r"(?:fingerprint: ([0-9a-f]{2}(?::[0-9a-f]{2}){7})\n)?$",
+    ))
     .unwrap();
     let captures = re
         .captures(&unparsed)
@@ -85,8 +259,7 @@ pub(crate) fn parse_public(unparsed: &[u8]) -> Result<(PublicKey, Ss58AddressFor
                 .into(),
         )
     }
-    let network = Ss58AddressFormat::try_from(&*network.to_ascii_lowercase())
-        .map_err(|_| format!("invalid network {}", network))?;
+    let network = parse_network_name(network)?;
     let mut pk = [0_u8; 42];
     assert_eq!(
         base64::decode_config_slice(&*data, base64::STANDARD, &mut pk)?,
@@ -96,18 +269,73 @@ pub(crate) fn parse_public(unparsed: &[u8]) -> Result<(PublicKey, Ss58AddressFor
         return Err("bad magic number in base64".to_owned().into())
     }
     let pk = ed25519_dalek::PublicKey::from_bytes(&pk[10..])?;
+    if let Some(expected) = captures.get(3) {
+        let actual = fingerprint(&pk);
+        if expected.as_bytes() != actual.as_bytes() {
+            return Err(crate::status::crypto(format!(
+                "Corrupt public key: its fingerprint is {}, but the file says {}",
+                actual,
+                str::from_utf8(expected.as_bytes()).unwrap()
+            )))
+        }
+    }
     Ok((pk, network))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    fn no_passphrase() -> Result<String, Error> { panic!("key is not encrypted") }
+    fn secret_error(secret: &[u8]) -> String {
+        parse_secret(secret, Ss58AddressFormat::PolkadotAccount, no_passphrase)
+            .unwrap_err()
+            .to_string()
+    }
     #[test]
-    #[should_panic(expected = "Ledgeracio secret keys are 88 bytes, not 87")]
-    fn too_short_rejected() { parse_secret(&[0; 87][..], Default::default()).unwrap(); }
+    fn too_short_rejected() {
+        assert_eq!(
+            secret_error(&GOOD_KEY[..87]),
+            "Truncated key file (expected 88 bytes, got 87)"
+        );
+        assert_eq!(
+            secret_error(&GOOD_KEY[..10]),
+            "Truncated key file (expected at least 24 bytes, got 10)"
+        );
+    }
     #[test]
-    #[should_panic(expected = "Ledgeracio secret keys are 88 bytes, not 89")]
-    fn too_long_rejected() { parse_secret(&[0; 89][..], Default::default()).unwrap(); }
+    fn too_long_rejected() {
+        let long = [GOOD_KEY, &[0][..]].concat();
+        assert_eq!(
+            secret_error(&long),
+            "Key file too long (expected 88 bytes, got 89)"
+        );
+    }
+    #[test]
+    fn secret_network_read_from_header() {
+        assert_eq!(secret_network(GOOD_KEY), Some(Ss58AddressFormat::PolkadotAccount));
+        assert_eq!(secret_network(&GOOD_KEY[..23]), None);
+        assert_eq!(secret_network(&[0; 88][..]), None);
+    }
+    #[test]
+    fn bad_magic_rejected() {
+        assert_eq!(
+            secret_error(&[0; 88][..]),
+            "Not a Ledgeracio secret key (bad magic)"
+        );
+        assert_eq!(
+            secret_error(
+                b"untrusted comment: Ledgeracio v2 network Kusama public key\n\
+                  RWRhYWFhYWFhYSMdKinQezkECIgYk38MFS4llWihG/NVp8KO37Hyn/r/\n"
+            ),
+            "Not a Ledgeracio secret key (bad magic).  This looks like a public key file."
+        );
+    }
+    #[test]
+    fn unsupported_version_rejected() {
+        let mut key = GOOD_KEY.to_vec();
+        key[21] = 3;
+        assert_eq!(secret_error(&key), "Unsupported key version 3");
+    }
     const BAD_KEY: &[u8] = &[
         0x4c, 0x65, 0x64, 0x67, 0x65, 0x72, 0x61, 0x63, 0x69, 0x6f, 0x20, 0x53, 0x65, 0x63, 0x72,
         0x65, 0x74, 0x20, 0x4b, 0x65, 0x79, 0x01, 0x00, 0x00, 0xbf, 0x5b, 0x4a, 0x93, 0x49, 0xfe,
@@ -119,7 +347,7 @@ mod tests {
     #[test]
     #[should_panic(expected = "Public and secret keys don’t match")]
     fn rejects_key_mismatch() {
-        parse_secret(BAD_KEY, Ss58AddressFormat::PolkadotAccount).unwrap();
+        parse_secret(BAD_KEY, Ss58AddressFormat::PolkadotAccount, no_passphrase).unwrap();
     }
     const GOOD_KEY: &[u8] = &[
         0x4c, 0x65, 0x64, 0x67, 0x65, 0x72, 0x61, 0x63, 0x69, 0x6f, 0x20, 0x53, 0x65, 0x63, 0x72,
@@ -130,15 +358,58 @@ mod tests {
         0x5a, 0x67, 0x1c, 0x05, 0xc7, 0x05, 0x8b, 0xfd, 0xee, 0xcc, 0x4c, 0x59, 0xeb,
     ];
     #[test]
-    fn accepts_good_key() { parse_secret(GOOD_KEY, Ss58AddressFormat::PolkadotAccount).unwrap(); }
+    fn accepts_good_key() {
+        parse_secret(GOOD_KEY, Ss58AddressFormat::PolkadotAccount, no_passphrase).unwrap();
+    }
     #[test]
     #[should_panic(
         expected = "Expected a key for network kusama, but got a key for network polkadot"
     )]
     fn rejects_wrong_network() {
-        parse_secret(GOOD_KEY, Ss58AddressFormat::KusamaAccount).unwrap();
+        parse_secret(GOOD_KEY, Ss58AddressFormat::KusamaAccount, no_passphrase).unwrap();
+    }
+
+    #[test]
+    fn wrong_network_rejected_before_passphrase() {
+        let keypair = Keypair::generate(&mut OsRng {});
+        let network = Ss58AddressFormat::KusamaAccount;
+        let file = secret_key_file(&keypair, network, Some("passphrase")).unwrap();
+        assert_eq!(
+            parse_secret(&file, Ss58AddressFormat::PolkadotAccount, no_passphrase)
+                .unwrap_err()
+                .to_string(),
+            "Expected a key for network polkadot, but got a key for network kusama"
+        );
     }
 
+    #[test]
+    fn good_key_round_trips() {
+        let keypair = parse_secret(GOOD_KEY, Ss58AddressFormat::PolkadotAccount, no_passphrase)
+            .unwrap();
+        let file = secret_key_file(&keypair, Ss58AddressFormat::PolkadotAccount, None).unwrap();
+        assert_eq!(&file[..], GOOD_KEY);
+    }
+    #[test]
+    fn fingerprint_works() {
+        let keypair = parse_secret(GOOD_KEY, Ss58AddressFormat::PolkadotAccount, no_passphrase)
+            .unwrap();
+        assert_eq!(fingerprint(&keypair.public), "8a:89:0e:1d:6f:f7:ce:44");
+    }
+    #[test]
+    fn encrypted_key_round_trips() {
+        let keypair = Keypair::generate(&mut OsRng {});
+        let network = Ss58AddressFormat::KusamaAccount;
+        let file = secret_key_file(&keypair, network, Some("correct horse")).unwrap();
+        assert_eq!(file.len(), ENCRYPTED_SECRET_KEY_LEN);
+        let parsed = parse_secret(&file, network, || Ok("correct horse".to_owned())).unwrap();
+        assert_eq!(parsed.to_bytes()[..], keypair.to_bytes()[..]);
+        assert_eq!(
+            parse_secret(&file, network, || Ok("battery staple".to_owned()))
+                .unwrap_err()
+                .to_string(),
+            "Wrong passphrase, or corrupt secret key"
+        );
+    }
     #[test]
     #[should_panic(expected = "Invalid public key")]
     fn too_many_lines_rejected() {
@@ -166,6 +437,56 @@ mod tests {
         .unwrap();
     }
     #[test]
+    fn fingerprint_line_checked() {
+        parse_public(
+            b"untrusted comment: Ledgeracio v2 network Kusama public key\n\
+            RWRhYWFhYWFhYSMdKinQezkECIgYk38MFS4llWihG/NVp8KO37Hyn/r/\n\
+            fingerprint: e6:fe:45:35:be:64:4b:f5\n",
+        )
+        .unwrap();
+        assert_eq!(
+            parse_public(
+                b"untrusted comment: Ledgeracio v2 network Kusama public key\n\
+                RWRhYWFhYWFhYSMdKinQezkECIgYk38MFS4llWihG/NVp8KO37Hyn/r/\n\
+                fingerprint: e6:fe:45:35:be:64:4b:f6\n",
+            )
+            .unwrap_err()
+            .to_string(),
+            "Corrupt public key: its fingerprint is e6:fe:45:35:be:64:4b:f5, but the file says \
+             e6:fe:45:35:be:64:4b:f6"
+        );
+    }
+    #[test]
+    fn public_key_file_round_trips() {
+        let keypair = Keypair::generate(&mut OsRng {});
+        let network = Ss58AddressFormat::PolkadotAccount;
+        let file = public_key_file(&keypair.public, network);
+        assert!(file.ends_with(&format!("fingerprint: {}\n", fingerprint(&keypair.public))));
+        assert_eq!(
+            parse_public(file.as_bytes()).unwrap(),
+            (keypair.public, network)
+        );
+    }
+    #[test]
+    fn westend_key_accepted() {
+        let (_, network) = parse_public(
+            b"untrusted comment: Ledgeracio v2 network Westend public key\n\
+            RWRhYWFhYWFhYSMdKinQezkECIgYk38MFS4llWihG/NVp8KO37Hyn/r/\n",
+        )
+        .unwrap();
+        assert_eq!(network, Ss58AddressFormat::SubstrateAccount);
+    }
+    #[test]
+    fn numeric_network_accepted() {
+        let (_, network) = parse_public(
+            b"untrusted comment: Ledgeracio v2 network 200 public key\n\
+            RWRhYWFhYWFhYSMdKinQezkECIgYk38MFS4llWihG/NVp8KO37Hyn/r/\n",
+        )
+        .unwrap();
+        assert_eq!(network, Ss58AddressFormat::Custom(200));
+        assert_eq!(network_name(network), "200");
+    }
+    #[test]
     #[should_panic(expected = "Invalid public key")]
     fn no_panic_wrong_base64() {
         parse_public(
@@ -21,44 +21,165 @@
 #![forbid(unsafe_code)]
 
 mod keyparse;
-mod parser;
+mod status;
 
 /// The version of keys supported
 pub const KEY_VERSION: u8 = 1;
 
+/// The version of passphrase-encrypted secret keys
+pub const ENCRYPTED_KEY_VERSION: u8 = 2;
+
 /// The magic number at the beginning of a secret key
 pub const KEY_MAGIC: &[u8] = &*b"Ledgeracio Secret Key";
 
+/// The oldest version of the Ledgeracio app that keys and allowlists can be
+/// uploaded to
+pub const MIN_APP_VERSION: (u16, u16, u16) = (1, 0, 0);
+
 use ledgeracio::{get_network, Error, HardStore};
-use sp_core::crypto::AccountId32 as AccountId;
-use std::{fmt::Debug,
+use sp_core::crypto::{AccountId32 as AccountId, Ss58Codec};
+use std::{collections::HashSet,
+          fmt::Debug,
           fs,
           io::{BufReader, BufWriter}};
-use structopt::StructOpt;
+use structopt::{clap::{AppSettings, ErrorKind, Shell},
+                StructOpt};
 use substrate_subxt::{sp_core, sp_core::crypto::Ss58AddressFormat};
 
-use ed25519_dalek::Keypair;
-use keyparse::{parse_public, parse_secret};
-use parser::parse as parse_allowlist;
+use ed25519_dalek::{Keypair, PublicKey};
+use keyparse::{fingerprint, network_name, parse_network_name, parse_public, parse_secret,
+               public_key_file, secret_key_file, secret_network};
+use ledgeracio::{allowlist::{self, parse as parse_allowlist}, OutputFormat};
 use std::{fs::OpenOptions, io::Write, os::unix::fs::OpenOptionsExt, path::PathBuf};
 use substrate_subxt::sp_core::H256;
+use zeroize::Zeroizing;
 
-async fn inner_main() -> Result<(), Error> {
-    env_logger::init();
-    let LedgeracioAllowlist { network, cmd } = LedgeracioAllowlist::from_args();
+async fn inner_main(args: LedgeracioAllowlist) -> Result<(), Error> {
+    let LedgeracioAllowlist {
+        network,
+        timeout,
+        skip_version_check,
+        verbose,
+        quiet,
+        json_errors: _,
+        deadline: _,
+        cmd,
+    } = args;
+    let mut logger =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn"));
+    match (quiet, verbose) {
+        (true, _) => logger.filter_level(log::LevelFilter::Off),
+        (false, 0) => &mut logger,
+        (false, 1) => logger.filter_level(log::LevelFilter::Debug),
+        (false, _) => logger.filter_level(log::LevelFilter::Trace),
+    }
+    .init();
 
-    let keystore = || HardStore::new(network);
-    really_inner_main(cmd, keystore, network).await?;
+    let cmd = match without_network(cmd)? {
+        Some(cmd) => cmd,
+        None => return Ok(()),
+    };
+    let network = match (network, &cmd) {
+        (Some(network), _) => network,
+        (None, AllowlistCommand::Sign { file, .. }) => {
+            let name = allowlist::text_network(BufReader::new(fs::File::open(file)?))?
+                .ok_or_else(|| {
+                    status::tag(
+                        status::Status::Usage,
+                        "--network is required, since the allowlist has no `; network` comment",
+                    )
+                })?;
+            parse_network_name(&name).map_err(|e| status::tag(status::Status::Usage, e))?
+        }
+        (None, AllowlistCommand::ExportPub { secret, .. }) => {
+            secret_network(&Zeroizing::new(fs::read(secret)?)).ok_or_else(|| {
+                status::tag(status::Status::Usage, "Not a Ledgeracio secret key (bad magic)")
+            })?
+        }
+        (None, AllowlistCommand::Merge { inputs, .. }) => {
+            let mut names = vec![];
+            for input in inputs {
+                names.extend(allowlist::text_network(BufReader::new(fs::File::open(input)?))?)
+            }
+            let name = names.first().ok_or_else(|| {
+                status::tag(
+                    status::Status::Usage,
+                    "--network is required, since no input has a `; network` comment",
+                )
+            })?;
+            parse_network_name(name).map_err(|e| status::tag(status::Status::Usage, e))?
+        }
+        (None, _) => return Err(status::tag(status::Status::Usage, "--network is required")),
+    };
+    let timeout = timeout.map(std::time::Duration::from_secs);
+    let keystore = || {
+        HardStore::new(network)
+            .map(|store| store.with_timeout(timeout))
+            .map_err(status::device)
+    };
+    really_inner_main(cmd, keystore, network, skip_version_check, quiet).await?;
     Ok(())
 }
 
+/// Run `cmd` if it needs no network, so that `--network` is not required for
+/// it.  Otherwise, return it to be run once the network is known.
+fn without_network(cmd: AllowlistCommand) -> Result<Option<AllowlistCommand>, Error> {
+    match cmd {
+        AllowlistCommand::Completions { shell } => LedgeracioAllowlist::clap()
+            .gen_completions_to("ledgeracio-allowlist", shell, &mut std::io::stdout()),
+        AllowlistCommand::Validate { file } => {
+            let (allowlist, network) = allowlist::validate(BufReader::new(fs::File::open(file)?))?;
+            match network {
+                Some(network) => println!(
+                    "Valid allowlist with nonce {} and {} addresses for network {}",
+                    allowlist.nonce,
+                    allowlist.addresses.len(),
+                    network_name(network)
+                ),
+                None => println!("Valid empty allowlist with nonce {}", allowlist.nonce),
+            }
+        }
+        AllowlistCommand::Fingerprint { file } => {
+            let digest = blake2b_simd::Params::new()
+                .hash_length(32)
+                .hash(&fs::read(file)?);
+            println!("{}", digest.to_hex())
+        }
+        cmd => return Ok(Some(cmd)),
+    }
+    Ok(None)
+}
+
+/// Whether `LEDGERACIO_JSON` asks for errors to be reported as JSON
+fn json_errors_from_env() -> bool {
+    std::env::var_os("LEDGERACIO_JSON").map_or(false, |value| !value.is_empty())
+}
+
+/// Whether errors should be reported as JSON, even if the arguments do not
+/// parse
+fn json_errors_requested() -> bool {
+    json_errors_from_env() || std::env::args_os().any(|arg| arg == "--json-errors")
+}
+
 fn main() {
-    match async_std::task::block_on(inner_main()) {
-        Ok(()) => (),
-        Err(e) => {
-            eprintln!("Error: {}", e);
-            std::process::exit(1)
+    let args = LedgeracioAllowlist::from_iter_safe(std::env::args_os()).unwrap_or_else(|e| {
+        match e.kind {
+            ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => e.exit(),
+            _ if json_errors_requested() => {
+                let error = status::tag(status::Status::Usage, e.message);
+                std::process::exit(status::report(&*error, true))
+            }
+            _ => {
+                eprintln!("{}", e.message);
+                std::process::exit(status::Status::Usage as i32)
+            }
         }
+    });
+    let json_errors = args.json_errors || json_errors_from_env();
+    let deadline = args.deadline.map(std::time::Duration::from_secs);
+    let command = ledgeracio::with_deadline(inner_main(args), deadline);
+    if let Err(e) = async_std::task::block_on(command) {
+        std::process::exit(status::report(&*e, json_errors))
     }
 }
 
@@ -68,9 +189,38 @@ fn main() {
     about = "Ledgeracio approved validator management CLI"
 )]
 struct LedgeracioAllowlist {
-    /// Network
+    /// Network.  This is required, except by `sign` and `merge` when a
+    /// textual allowlist has a `; network` comment, as written by `inspect`,
+    /// and by `export-pub`, which uses the network of the secret key.  If both
+    /// are given, this takes precedence.  `completions`, `fingerprint`, and
+    /// `validate` ignore it.
     #[structopt(long, parse(try_from_str = get_network))]
-    network: Ss58AddressFormat,
+    network: Option<Ss58AddressFormat>,
+    /// Give up waiting for the Ledger device after this many seconds, except
+    /// while it waits for the user to confirm.  By default, wait forever.
+    #[structopt(long)]
+    timeout: Option<u64>,
+    /// Do not check the version of the Ledgeracio app before uploading to it.
+    #[structopt(long = "skip-version-check")]
+    skip_version_check: bool,
+    /// Log more.  `-v` logs debug messages, and `-vv` also logs the exchanges
+    /// with the Ledger device.  Without this or `--quiet`, `RUST_LOG` is used,
+    /// and defaults to logging warnings.
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    verbose: u8,
+    /// Do not log anything, or show progress while uploading.
+    #[structopt(short = "q", long = "quiet", conflicts_with = "verbose")]
+    quiet: bool,
+    /// Report failures on stderr as a JSON object with the message (`error`),
+    /// the class of failure (`kind`: `usage`, `device`, `crypto`, `io`, or
+    /// `other`), and the exit status (`code`).  Setting `LEDGERACIO_JSON` to a
+    /// non-empty value does the same.
+    #[structopt(long = "json-errors")]
+    json_errors: bool,
+    /// Give up if the whole command, not just one exchange with the device,
+    /// takes longer than this many seconds.  By default, there is no limit.
+    #[structopt(long)]
+    deadline: Option<u64>,
     /// Subcommand
     #[structopt(subcommand)]
     cmd: AllowlistCommand,
@@ -79,7 +229,23 @@ struct LedgeracioAllowlist {
 #[derive(StructOpt, Debug)]
 pub(crate) enum AllowlistCommand {
     /// Upload a new approved validator list.  This list must be signed.
-    Upload { path: PathBuf },
+    Upload {
+        path: PathBuf,
+        /// Check the list and print what would be uploaded, without
+        /// contacting the device.  The signature is not checked.
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+        /// Do not check that the list is signed by the signing key on the
+        /// device before uploading it.
+        #[structopt(long = "no-verify")]
+        no_verify: bool,
+        /// Refuse to upload the list if it is older than this, such as `90m`,
+        /// `12h`, or `7d`.  A number without a unit is in seconds.  Allowlists
+        /// do not record when they were signed, so the modification time of the
+        /// file is used.
+        #[structopt(long = "max-age", parse(try_from_str = parse_duration))]
+        max_age: Option<std::time::Duration>,
+    },
     /// Set the validator list signing key.  This will fail if a signing key has
     /// already been set.
     SetKey {
@@ -89,14 +255,41 @@ pub(crate) enum AllowlistCommand {
     },
     /// Get the validator list signing key.  This will fail unless a signing key
     /// has been set.
-    GetKey,
+    GetKey {
+        /// The output format: `text` (the default) or `json`.  JSON output is
+        /// an object with the network, the base64-encoded key, and its
+        /// fingerprint.
+        #[structopt(long = "format", default_value = "text")]
+        format: OutputFormat,
+    },
     /// Generate a new signing key.
     GenKey {
         /// Prefix of the file to write the keys to
         ///
         /// The public key will be written to `file.pub` and the secret key
         /// to `file.sec`.
-        file: PathBuf,
+        #[structopt(required_unless = "stdout", conflicts_with = "stdout")]
+        file: Option<PathBuf>,
+        /// Print the keys to stdout instead of writing them to files.  The
+        /// secret key is printed base64-encoded, as `sign` reads it from
+        /// `LEDGERACIO_SECRET`.  Anyone who can see the output can sign
+        /// allowlists.
+        #[structopt(long = "stdout")]
+        stdout: bool,
+        /// Encrypt the secret key with a passphrase, which will be prompted
+        /// for.  The passphrase is then required whenever the key is used.
+        #[structopt(long = "encrypt")]
+        encrypt: bool,
+        /// The permissions of the secret key file, in octal.  World-writable
+        /// modes are rejected.  The public key file is always created with mode
+        /// 444.
+        #[structopt(long = "mode", default_value = "400", parse(try_from_str = parse_mode))]
+        mode: u32,
+        /// Overwrite `file.pub` and `file.sec` if they already exist.  By
+        /// default, `gen-key` refuses to, so that an existing key pair is
+        /// not destroyed.
+        #[structopt(long = "force", conflicts_with = "stdout")]
+        force: bool,
     },
     /// Compile the provided textual allowlist into a binary format and sign it.
     ///
@@ -110,15 +303,23 @@ pub(crate) enum AllowlistCommand {
         /// The textual allowlist format is very simple.  If a line is empty, or
         /// if its first non-whitespace character is `;` or `#`, it is
         /// considered a comment.  Otherwise, the line must be a valid SS58
-        /// address for the provided network, except that leading and
-        /// trailing whitespace are ignored.  The process of compiling
+        /// address for the provided network, or a raw account ID written as
+        /// `0x` followed by 64 hex digits, except that leading and
+        /// trailing whitespace are ignored.  An address may be followed by a
+        /// `;` comment, which is used as its label.  The process of compiling
         /// an allowlist to binary format and signing it is completely
         /// deterministic.
         #[structopt(short = "f", long = "file")]
         file: PathBuf,
-        /// The secret key file.
+        /// The secret key file.  If neither this nor `--secret-fd` is given,
+        /// the base64-encoded contents of the secret key file are read from
+        /// the `LEDGERACIO_SECRET` environment variable.
         #[structopt(short = "s", long = "secret")]
-        secret: PathBuf,
+        secret: Option<PathBuf>,
+        /// Read the secret key file from this file descriptor, so that it never
+        /// needs to be stored on disk.
+        #[structopt(long = "secret-fd", conflicts_with = "secret")]
+        secret_fd: Option<u32>,
         /// The output file
         #[structopt(short = "o", long = "output")]
         output: PathBuf,
@@ -126,9 +327,35 @@ pub(crate) enum AllowlistCommand {
         /// the same key, and is used to prevent replay attacks.
         #[structopt(short = "n", long = "nonce")]
         nonce: u32,
+        /// Drop duplicate addresses instead of rejecting the file.  Addresses
+        /// are compared after decoding, so different spellings of the same
+        /// account are duplicates too.
+        #[structopt(long = "dedup")]
+        dedup: bool,
+        /// Sort the addresses by account ID before compiling, so that the
+        /// output depends only on the set of addresses and not their order.
+        /// This changes the signed bytes, and therefore the signature,
+        /// compared to an unsorted list.
+        #[structopt(long = "sort")]
+        sort: bool,
+        /// The maximum number of addresses allowed, counted after any
+        /// duplicates are dropped.  Use this to catch lists that are too large
+        /// for the device before uploading them.
+        #[structopt(long = "limit")]
+        limit: Option<usize>,
+        /// Write the labels of the addresses to this TOML file.  Labels are
+        /// never signed, and do not change the output.
+        #[structopt(long = "annotations")]
+        annotations: Option<PathBuf>,
+        /// Read `file` as CSV with a header row, and take the addresses from
+        /// the column with this name.  Other columns are ignored.  Every cell
+        /// in the column must be an address.
+        #[structopt(long = "csv-column")]
+        csv_column: Option<String>,
     },
-    /// Inspect the given allowlist file and verify its signature. The output is
-    /// in a format suitable for `ledgeracio sign`.
+    /// Inspect the given allowlist file and verify its signature. The default
+    /// output is in a format suitable for `ledgeracio sign`: signing it with
+    /// the same key and nonce reproduces the original file exactly.
     Inspect {
         /// The binary allowlist file to read
         #[structopt(short = "f", long = "file")]
@@ -139,15 +366,280 @@ pub(crate) enum AllowlistCommand {
         /// The output file.  Defaults to stdout.
         #[structopt(short = "o", long = "output")]
         output: Option<PathBuf>,
+        /// The output format: `text` (the default) or `json`.  JSON output is
+        /// an object with the nonce, the network, the base64-encoded signing
+        /// key and its fingerprint, and the array of addresses.
+        #[structopt(long = "format", default_value = "text")]
+        format: OutputFormat,
+        /// A TOML file of address labels, as written by `sign
+        /// --annotations`.  In text output, each label is printed as a
+        /// trailing comment after its address.
+        #[structopt(long = "annotations")]
+        annotations: Option<PathBuf>,
+        /// Print only the number of addresses and the nonce, instead of the
+        /// addresses.  In JSON output, the object has `nonce` and `count`.
+        #[structopt(long = "count")]
+        count: bool,
+    },
+    /// Re-sign an existing signed allowlist with a new key.
+    ///
+    /// The signature of `file` is verified with the old public key, and the
+    /// addresses it contains are signed with the new secret key.  The address
+    /// order is preserved unless `--sort` is given.
+    RotateKey {
+        /// The binary allowlist file to re-sign
+        #[structopt(short = "f", long = "file")]
+        file: PathBuf,
+        /// The public key file that signed `file`.
+        #[structopt(long = "old-public")]
+        old_public: PathBuf,
+        /// The new secret key file.
+        #[structopt(long = "new-secret")]
+        new_secret: PathBuf,
+        /// The output file
+        #[structopt(short = "o", long = "output")]
+        output: PathBuf,
+        /// The nonce of the new list.
+        #[structopt(short = "n", long = "nonce")]
+        nonce: u32,
+        /// Drop duplicate addresses instead of failing.
+        #[structopt(long = "dedup")]
+        dedup: bool,
+        /// Sort the addresses by account ID.
+        #[structopt(long = "sort")]
+        sort: bool,
+    },
+    /// Check that a secret key and a public key file match, and print the
+    /// fingerprint of the key.
+    ///
+    /// This fails if the keys do not match or are for different networks.
+    VerifyKey {
+        /// The secret key file.
+        #[structopt(short = "s", long = "secret")]
+        secret: PathBuf,
+        /// The public key file.
+        #[structopt(short = "p", long = "public")]
+        public: PathBuf,
+    },
+    /// Recreate the public key file of a secret key.
+    ///
+    /// The public key file is written for the network stored in the secret
+    /// key, which is used if `--network` is not given.  If the secret key is
+    /// encrypted, its passphrase is prompted for.
+    ExportPub {
+        /// The secret key file.
+        #[structopt(short = "s", long = "secret")]
+        secret: PathBuf,
+        /// The public key file to write.  It must not exist yet.
+        #[structopt(short = "o", long = "output")]
+        output: PathBuf,
+    },
+    /// Combine textual allowlists into one, ready for `sign`.
+    ///
+    /// Each address is kept the first time it appears, with its label.  The
+    /// output begins with a `; network` comment.  Every input that has a `;
+    /// network` comment must be for the same network, which is used if
+    /// `--network` is not given.
+    Merge {
+        /// The textual allowlists to combine
+        #[structopt(required = true)]
+        inputs: Vec<PathBuf>,
+        /// The output file
+        #[structopt(short = "o", long = "output")]
+        output: PathBuf,
+        /// Also copy the comment lines of the inputs, except the `; nonce`,
+        /// `; network`, and `; signed by key` comments written by `inspect`.
+        #[structopt(long = "keep-comments")]
+        keep_comments: bool,
+    },
+    /// Compare two signed allowlist files, verifying both signatures.
+    ///
+    /// Addresses only in the old list are printed prefixed with `-`, and
+    /// addresses only in the new list are printed prefixed with `+`.  This
+    /// fails if the nonce of the new list is not greater than that of the old
+    /// list.
+    Diff {
+        /// The old binary allowlist file
+        old: PathBuf,
+        /// The new binary allowlist file
+        new: PathBuf,
+        /// The public key file that signed both lists.
+        #[structopt(short = "p", long = "public")]
+        public: PathBuf,
+    },
+    /// Print the BLAKE2b-256 hash of a signed allowlist file, in hexadecimal.
+    ///
+    /// This identifies the exact file, independent of any key, so that two
+    /// people can confirm that they hold the same list.
+    Fingerprint {
+        /// The binary allowlist file
+        file: PathBuf,
+    },
+    /// Check that a signed allowlist file is structurally sound, without
+    /// checking its signature.
+    ///
+    /// No key, device, or `--network` is needed: the addresses only need to
+    /// all be for the same network.
+    Validate {
+        /// The binary allowlist file
+        file: PathBuf,
+    },
+    /// Print a completion script for `shell` to stdout.
+    #[structopt(setting = AppSettings::Hidden)]
+    Completions {
+        /// The shell to generate completions for
+        #[structopt(possible_values = &Shell::variants(), case_insensitive = true)]
+        shell: Shell,
     },
 }
 
-fn write(buf: &[&[u8]], path: &std::path::Path) -> std::io::Result<()> {
+/// Prompt for the passphrase of an encrypted secret key
+fn read_passphrase() -> Result<String, Error> {
+    Ok(rpassword::read_password_from_tty(Some("Passphrase: "))?)
+}
+
+/// The environment variable that `sign` reads a base64-encoded secret key from
+const SECRET_ENV: &str = "LEDGERACIO_SECRET";
+
+/// Read a secret key file from `path`, the file descriptor `fd`, or the
+/// environment.  The variable is removed from the environment once read.
+fn read_secret(path: Option<PathBuf>, fd: Option<u32>) -> Result<Zeroizing<Vec<u8>>, Error> {
+    if let Some(path) = path {
+        return Ok(Zeroizing::new(fs::read(path)?))
+    }
+    if let Some(fd) = fd {
+        return Ok(Zeroizing::new(fs::read(format!("/dev/fd/{}", fd))?))
+    }
+    let encoded = match std::env::var(SECRET_ENV) {
+        Ok(encoded) => Zeroizing::new(encoded),
+        Err(_) => {
+            return Err(format!(
+                "No secret key given: pass --secret or --secret-fd, or set {}",
+                SECRET_ENV
+            )
+            .into())
+        }
+    };
+    std::env::remove_var(SECRET_ENV);
+    base64::decode(encoded.trim())
+        .map(Zeroizing::new)
+        .map_err(|e| format!("{} is not valid base64: {}", SECRET_ENV, e).into())
+}
+
+/// Convert the column named `column` of the CSV file at `path` to a textual
+/// allowlist.  Each address is put on the same line as in the CSV file, so
+/// that parse errors give the right line number.
+fn csv_to_text(path: &std::path::Path, column: &str) -> Result<String, Error> {
+    let mut reader = csv::Reader::from_path(path)?;
+    let index = reader
+        .headers()?
+        .iter()
+        .position(|header| header.trim() == column)
+        .ok_or_else(|| {
+            status::tag(
+                status::Status::Usage,
+                format!("{} has no column named {}", path.display(), column),
+            )
+        })?;
+    let mut text = String::new();
+    let mut line = 1;
+    for record in reader.records() {
+        let record = record?;
+        let row = record.position().map_or(line, csv::Position::line);
+        let cell = record.get(index).unwrap_or_default().trim();
+        // Anything that the textual format would treat as a comment, a blank
+        // line, or more than one line must not be silently accepted
+        if cell.is_empty() || cell.contains(&[';', '#', '\n', '\r'][..]) {
+            return Err(status::tag(
+                status::Status::Usage,
+                format!("line {} of {}: {:?} is not an address", row, path.display(), cell),
+            ))
+        }
+        while line < row {
+            text.push('\n');
+            line += 1
+        }
+        text.push_str(cell);
+        text.push('\n');
+        line += 1
+    }
+    Ok(text)
+}
+
+/// Parse an octal file mode for a key file
+fn parse_mode(mode: &str) -> Result<u32, Error> {
+    let mode = u32::from_str_radix(mode, 8)?;
+    if mode & !0o777 != 0 {
+        Err(format!("Mode {:o} has bits other than permission bits set", mode).into())
+    } else if mode & 0o002 != 0 {
+        Err(format!("Refusing to create a world-writable key file (mode {:o})", mode).into())
+    } else {
+        Ok(mode)
+    }
+}
+
+/// Parse a duration such as `30`, `90m`, `12h`, or `7d`
+fn parse_duration(duration: &str) -> Result<std::time::Duration, Error> {
+    let (number, unit) = match duration.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => duration.split_at(index),
+        None => (duration, "s"),
+    };
+    let multiplier = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("Invalid unit in {}: use s, m, h, or d", duration).into()),
+    };
+    number
+        .parse::<u64>()?
+        .checked_mul(multiplier)
+        .map(std::time::Duration::from_secs)
+        .ok_or_else(|| format!("Duration {} is too long", duration).into())
+}
+
+/// Fail if the file at `path` was last modified more than `max_age` ago
+fn check_age(path: &std::path::Path, max_age: std::time::Duration) -> Result<(), Error> {
+    log::warn!(
+        "{} does not record when it was signed, so its modification time is used",
+        path.display()
+    );
+    let modified = fs::metadata(path)?.modified()?;
+    // A modification time in the future counts as new
+    let age = modified.elapsed().unwrap_or_default();
+    if age > max_age {
+        Err(status::tag(
+            status::Status::Usage,
+            format!(
+                "{} was modified {} seconds ago, which is longer than --max-age allows",
+                path.display(),
+                age.as_secs()
+            ),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that the app on the device is at least [`MIN_APP_VERSION`]
+async fn check_app_version(hardware: &HardStore) -> Result<(), Error> {
+    let version = hardware.app_version().await.map_err(status::device)?;
+    if version < MIN_APP_VERSION {
+        Err(status::device(format!(
+            "App version {}.{}.{} too old, need >= {}.{}.{}",
+            version.0, version.1, version.2, MIN_APP_VERSION.0, MIN_APP_VERSION.1, MIN_APP_VERSION.2
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Write a new file, failing if it already exists
+fn write(buf: &[&[u8]], path: &std::path::Path, mode: u32) -> std::io::Result<()> {
     let mut f = OpenOptions::new()
-        .mode(0o400)
+        .mode(mode)
         .write(true)
-        .create(true)
-        .truncate(true)
+        .create_new(true)
         .open(path)?;
     for i in buf {
         f.write_all(i)?;
@@ -159,84 +651,217 @@ async fn really_inner_main<T: FnOnce() -> Result<ledgeracio::HardStore, Error>>(
     acl: AllowlistCommand,
     hardware: T,
     network: Ss58AddressFormat,
+    skip_version_check: bool,
+    quiet: bool,
 ) -> Result<Option<H256>, Error> {
     match acl {
-        AllowlistCommand::GetKey => {
-            let s: [u8; 32] = hardware()?.get_pubkey().await?;
-            println!("Public key is {}", base64::encode(s));
+        AllowlistCommand::GetKey { format } => {
+            let s: [u8; 32] = hardware()?.get_pubkey().await.map_err(status::device)?;
+            let fingerprint = fingerprint(&PublicKey::from_bytes(&s)?);
+            match format {
+                OutputFormat::Text => {
+                    println!("Public key is {}", base64::encode(s));
+                    println!("Fingerprint: {}", fingerprint);
+                }
+                OutputFormat::Json => println!(
+                    "{}",
+                    serde_json::json!({
+                        "network": network_name(network),
+                        "public_key_base64": base64::encode(s),
+                        "fingerprint": fingerprint,
+                    })
+                ),
+            }
         }
         AllowlistCommand::SetKey { key } => {
             let (key, key_network) = parse_public(&*fs::read(key)?)?;
             if key_network != network {
-                return Err(format!(
-                    "Key is for network {}, not {}",
-                    String::from(key_network),
-                    String::from(network)
-                )
-                .into())
+                return Err(status::tag(
+                    status::Status::Usage,
+                    format!(
+                        "Key is for network {}, not {}",
+                        String::from(key_network),
+                        String::from(network)
+                    ),
+                ))
+            }
+            let hardware = hardware()?;
+            if !skip_version_check {
+                check_app_version(&hardware).await?
             }
-            hardware()?.set_pubkey(&key.as_bytes()).await?
+            hardware
+                .set_pubkey(&key.as_bytes())
+                .await
+                .map_err(status::device)?
         }
-        AllowlistCommand::Upload { path } => {
-            let allowlist = fs::read(path)?;
-            hardware()?.allowlist_upload(&allowlist).await?
+        AllowlistCommand::Upload {
+            path,
+            dry_run,
+            no_verify,
+            max_age,
+        } => {
+            if let Some(max_age) = max_age {
+                check_age(&path, max_age)?
+            }
+            let signed = fs::read(path)?;
+            if dry_run {
+                let decoded = allowlist::decode(&*signed, network)?;
+                println!(
+                    "Would upload an allowlist with nonce {} and {} addresses:",
+                    decoded.nonce,
+                    decoded.addresses.len()
+                );
+                for i in decoded.addresses {
+                    println!("{}", i)
+                }
+            } else {
+                let hardware = hardware()?;
+                if !skip_version_check {
+                    check_app_version(&hardware).await?
+                }
+                if !no_verify {
+                    let decoded = allowlist::decode(&*signed, network)?;
+                    let key = hardware.get_pubkey().await.map_err(status::device)?;
+                    let message = "Allowlist is not signed by the signing key on the device";
+                    decoded
+                        .verify(&PublicKey::from_bytes(&key)?)
+                        .map_err(|_| status::crypto(message))?
+                }
+                // The device library splits the list into chunks itself, so
+                // the best that can be shown is a spinner
+                let progress = if quiet {
+                    indicatif::ProgressBar::hidden()
+                } else {
+                    indicatif::ProgressBar::new_spinner()
+                };
+                progress.set_message(&format!("Uploading {} bytes", signed.len()));
+                progress.enable_steady_tick(100);
+                let uploaded = hardware.allowlist_upload(&signed).await;
+                progress.finish_and_clear();
+                uploaded.map_err(status::device)?
+            }
         }
-        AllowlistCommand::GenKey { mut file } => {
-            if file.extension().is_some() {
+        AllowlistCommand::GenKey {
+            file,
+            stdout,
+            encrypt,
+            mode,
+            force,
+        } => {
+            if let Some(file) = file.as_ref().filter(|file| file.extension().is_some()) {
                 return Err(format!(
                     "please provide a filename with no extension, not {}",
                     file.display()
                 )
                 .into())
             }
+            if let Some(file) = &file {
+                for extension in &["pub", "sec"] {
+                    let path = file.with_extension(extension);
+                    if path.exists() && !force {
+                        return Err(format!(
+                            "{} already exists; pass --force to overwrite it",
+                            path.display()
+                        )
+                        .into())
+                    }
+                }
+            }
+            let passphrase = if encrypt {
+                let passphrase = Zeroizing::new(read_passphrase()?);
+                if passphrase.is_empty() {
+                    return Err("The passphrase must not be empty".into())
+                }
+                let repeated =
+                    Zeroizing::new(rpassword::read_password_from_tty(Some("Repeat passphrase: "))?);
+                if *passphrase != *repeated {
+                    return Err("Passphrases do not match".into())
+                }
+                Some(passphrase)
+            } else {
+                None
+            };
             let keypair = Keypair::generate(&mut rand::rngs::OsRng {});
-            let secretkey = keypair.secret.to_bytes();
-            let publickey = keypair.public.to_bytes();
-            let mut thevec = b"Edaaaaaaaa"[..].to_owned();
-            thevec.extend(publickey[..].iter());
-            file.set_extension("pub");
-            let public = format!(
-                "untrusted comment: Ledgeracio v2 network {} public key\n{}\n",
-                match network {
-                    Ss58AddressFormat::KusamaAccount => "Kusama",
-                    Ss58AddressFormat::PolkadotAccount => "Polkadot",
-                    _ => unreachable!("should have been rejected earlier"),
-                },
-                base64::encode(&thevec[..])
-            );
-            write(&[public.as_bytes()], &file)?;
-            file.set_extension("sec");
-            write(
-                &[
-                    KEY_MAGIC,
-                    &u16::from(KEY_VERSION).to_le_bytes(),
-                    &[network.into()],
-                    &secretkey[..],
-                    &publickey[..],
-                ],
-                &file,
-            )?;
+            let public = public_key_file(&keypair.public, network);
+            let secret =
+                secret_key_file(&keypair, network, passphrase.as_ref().map(|p| p.as_str()))?;
+            match file {
+                None => {
+                    debug_assert!(stdout, "structopt requires a file without --stdout");
+                    eprintln!(
+                        "WARNING: the secret key is printed below.  Anyone who can read it can \
+                         sign allowlists."
+                    );
+                    print!("Public key file:\n{}", public);
+                    let encoded = Zeroizing::new(base64::encode(&secret[..]));
+                    println!("Secret key (base64):\n{}", *encoded);
+                }
+                Some(mut file) => {
+                    if force {
+                        // The old files may be read-only, so replace them
+                        // rather than truncating them
+                        for extension in &["pub", "sec"] {
+                            match fs::remove_file(file.with_extension(extension)) {
+                                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                                    return Err(e.into())
+                                }
+                                _ => (),
+                            }
+                        }
+                    }
+                    file.set_extension("pub");
+                    write(&[public.as_bytes()], &file, 0o444)?;
+                    file.set_extension("sec");
+                    write(&[&secret[..]], &file, mode)?;
+                }
+            }
+            println!("Fingerprint: {}", fingerprint(&keypair.public));
         }
         AllowlistCommand::Sign {
             file,
             secret,
+            secret_fd,
             output,
             nonce,
+            dedup,
+            sort,
+            limit,
+            annotations,
+            csv_column,
         } => {
-            let file = BufReader::new(fs::File::open(file)?);
-            let secret: Vec<u8> = fs::read(secret)?;
-            let Keypair { public, secret } = parse_secret(&*secret, network)?;
-            let signed =
-                parse_allowlist::<_, AccountId>(file, network, &public, &(&secret).into(), nonce)?;
+            let file: Box<dyn std::io::BufRead> = match csv_column {
+                Some(column) => Box::new(std::io::Cursor::new(csv_to_text(&file, &column)?)),
+                None => Box::new(BufReader::new(fs::File::open(file)?)),
+            };
+            let secret = read_secret(secret, secret_fd)?;
+            let Keypair { public, secret } = parse_secret(&*secret, network, read_passphrase)?;
+            let (signed, labels) = allowlist::parse_annotated::<_, AccountId>(
+                file,
+                network,
+                &public,
+                &(&secret).into(),
+                nonce,
+                allowlist::Options { dedup, sort, limit },
+            )?;
             fs::write(output, signed)?;
+            if let Some(annotations) = annotations {
+                fs::write(annotations, toml::to_string(&labels)?)?;
+            }
         }
         AllowlistCommand::Inspect {
             file,
             public,
             output,
+            format,
+            annotations,
+            count,
         } => {
             let file = BufReader::new(fs::File::open(file)?);
             let (pk, network) = parse_public(&*fs::read(public)?)?;
+            let labels: allowlist::Annotations = match annotations {
+                Some(path) => toml::from_str(&fs::read_to_string(path)?)?,
+                None => allowlist::Annotations::new(),
+            };
             let stdout = std::io::stdout();
             let mut output = BufWriter::new(match output {
                 None => Box::new(stdout.lock()) as Box<dyn std::io::Write>,
@@ -250,10 +875,176 @@ async fn really_inner_main<T: FnOnce() -> Result<ledgeracio::HardStore, Error>>(
                 ),
             });
 
-            for i in crate::parser::inspect::<_, AccountId>(file, network, &pk)? {
-                writeln!(output, "{}", i)?;
+            let (nonce, addresses) = allowlist::inspect::<_, AccountId>(file, network, &pk)?;
+            match format {
+                OutputFormat::Text if count => {
+                    writeln!(output, "{} addresses, nonce {}", addresses.len(), nonce)?
+                }
+                OutputFormat::Json if count => writeln!(
+                    output,
+                    "{}",
+                    serde_json::json!({ "nonce": nonce, "count": addresses.len() })
+                )?,
+                OutputFormat::Text => allowlist::write_text(
+                    &mut output,
+                    nonce,
+                    &network_name(network),
+                    &fingerprint(&pk),
+                    &addresses,
+                    &labels,
+                )?,
+                OutputFormat::Json => writeln!(
+                    output,
+                    "{}",
+                    serde_json::json!({
+                        "nonce": nonce,
+                        "network": network_name(network),
+                        "public_key": base64::encode(pk.as_bytes()),
+                        "fingerprint": fingerprint(&pk),
+                        "addresses": addresses,
+                    })
+                )?,
+            }
+        }
+        AllowlistCommand::RotateKey {
+            file,
+            old_public,
+            new_secret,
+            output,
+            nonce,
+            dedup,
+            sort,
+        } => {
+            let file = BufReader::new(fs::File::open(file)?);
+            let (old_public, key_network) = parse_public(&*fs::read(old_public)?)?;
+            let (_, addresses) =
+                allowlist::inspect::<_, AccountId>(file, key_network, &old_public)?;
+            let secret = Zeroizing::new(fs::read(new_secret)?);
+            let Keypair { public, secret } = parse_secret(&*secret, key_network, read_passphrase)?;
+            let signed = parse_allowlist::<_, AccountId>(
+                addresses.join("\n").as_bytes(),
+                key_network,
+                &public,
+                &(&secret).into(),
+                nonce,
+                allowlist::Options {
+                    dedup,
+                    sort,
+                    limit: None,
+                },
+            )?;
+            fs::write(output, signed)?;
+        }
+        AllowlistCommand::VerifyKey { secret, public } => {
+            let (public, key_network) = parse_public(&*fs::read(public)?)?;
+            let secret = Zeroizing::new(fs::read(secret)?);
+            let keypair = parse_secret(&*secret, key_network, read_passphrase)?;
+            if keypair.public != public {
+                return Err(status::crypto("Secret key does not match public key"))
+            }
+            println!(
+                "Keys match.  Network: {}, fingerprint: {}",
+                network_name(key_network),
+                fingerprint(&public)
+            );
+        }
+        AllowlistCommand::ExportPub { secret, output } => {
+            let secret = Zeroizing::new(fs::read(secret)?);
+            let keypair = parse_secret(&*secret, network, read_passphrase)?;
+            write(&[public_key_file(&keypair.public, network).as_bytes()], &output, 0o444)?;
+            println!("Fingerprint: {}", fingerprint(&keypair.public));
+        }
+        AllowlistCommand::Merge {
+            inputs,
+            output,
+            keep_comments,
+        } => {
+            let mut seen = HashSet::new();
+            let mut merged = format!("; network {}\n", network_name(network));
+            for input in inputs {
+                let text = fs::read(&input)?;
+                if let Some(name) = allowlist::text_network(&*text)? {
+                    let input_network = parse_network_name(&name)
+                        .map_err(|e| status::tag(status::Status::Usage, e))?;
+                    if input_network != network {
+                        return Err(status::tag(
+                            status::Status::Usage,
+                            format!(
+                                "{} is for network {}, not {}",
+                                input.display(),
+                                name,
+                                network_name(network)
+                            ),
+                        ))
+                    }
+                }
+                let lines = allowlist::read_text(&*text, network).map_err(|e| {
+                    std::io::Error::new(e.kind(), format!("{}: {}", input.display(), e))
+                })?;
+                for line in lines {
+                    match line {
+                        allowlist::TextLine::Comment(comment) => {
+                            let header = ["nonce ", "network ", "signed by key "];
+                            let body = comment[1..].trim_start();
+                            if keep_comments && !header.iter().any(|h| body.starts_with(h)) {
+                                merged.push_str(&comment);
+                                merged.push('\n')
+                            }
+                        }
+                        allowlist::TextLine::Address(account, label) => {
+                            if !seen.insert(account.clone()) {
+                                continue
+                            }
+                            merged.push_str(&account.to_ss58check_with_version(network));
+                            if let Some(label) = label {
+                                merged.push_str(" ; ");
+                                merged.push_str(&label)
+                            }
+                            merged.push('\n')
+                        }
+                    }
+                }
+            }
+            fs::write(output, merged)?
+        }
+        AllowlistCommand::Diff { old, new, public } => {
+            let (pk, network) = parse_public(&*fs::read(public)?)?;
+            let inspect = |path: PathBuf| -> Result<_, Error> {
+                let file = BufReader::new(fs::File::open(path)?);
+                let (nonce, addresses) =
+                    allowlist::inspect::<_, AccountId>(file, network, &pk)?;
+                let mut decoded = vec![];
+                for address in addresses {
+                    let (account, _) = ledgeracio::parse_address::<AccountId>(&address)?;
+                    decoded.push((address, account))
+                }
+                Ok((nonce, decoded))
+            };
+            let (old_nonce, old) = inspect(old)?;
+            let (new_nonce, new) = inspect(new)?;
+            if new_nonce <= old_nonce {
+                return Err(format!(
+                    "New allowlist has nonce {}, which is not greater than the old nonce {}",
+                    new_nonce, old_nonce
+                )
+                .into())
+            }
+            let old_set: HashSet<_> = old.iter().map(|(_, account)| account).collect();
+            let new_set: HashSet<_> = new.iter().map(|(_, account)| account).collect();
+            for (address, account) in &old {
+                if !new_set.contains(account) {
+                    println!("-{}", address)
+                }
+            }
+            for (address, account) in &new {
+                if !old_set.contains(account) {
+                    println!("+{}", address)
+                }
             }
         }
+        AllowlistCommand::Completions { .. }
+        | AllowlistCommand::Validate { .. }
+        | AllowlistCommand::Fingerprint { .. } => unreachable!("run by without_network"),
     }
     Ok(None)
 }
@@ -0,0 +1,141 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of ledgeracio.
+//
+// ledgeracio is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ledgeracio is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ledgeracio.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Exit statuses of `ledgeracio-allowlist`
+
+use ledgeracio::{allowlist::Forged, DeviceError, Error};
+
+/// A class of failure, with its exit status.  Any other failure exits with
+/// status 1.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Status {
+    /// Bad arguments, or an input file that is malformed or for the wrong
+    /// network
+    Usage = 2,
+    /// The Ledger device could not be used, or refused the operation
+    Device = 3,
+    /// A signature did not verify, a passphrase was wrong, or keys did not
+    /// match
+    Crypto = 4,
+    /// Reading or writing a file failed
+    Io = 5,
+}
+
+/// An error tagged with the class of failure it represents
+#[derive(Debug)]
+pub(crate) struct Failure {
+    status: Status,
+    error: Error,
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        self.error.fmt(f)
+    }
+}
+
+impl std::error::Error for Failure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> { self.error.source() }
+}
+
+/// Tag `error` with `status`
+pub(crate) fn tag<T: Into<Error>>(status: Status, error: T) -> Error {
+    Box::new(Failure {
+        status,
+        error: error.into(),
+    })
+}
+
+/// Tag `error` as a device failure
+pub(crate) fn device<T: Into<Error>>(error: T) -> Error { tag(Status::Device, error) }
+
+/// Tag `error` as a cryptographic failure
+pub(crate) fn crypto<T: Into<Error>>(error: T) -> Error { tag(Status::Crypto, error) }
+
+/// The exit status for `error`
+pub(crate) fn exit_code(error: &(dyn std::error::Error + 'static)) -> i32 {
+    if let Some(failure) = error.downcast_ref::<Failure>() {
+        failure.status as i32
+    } else if let Some(error) = error.downcast_ref::<std::io::Error>() {
+        match error.get_ref() {
+            Some(inner) if inner.is::<Failure>() => exit_code(inner),
+            Some(inner) if inner.is::<Forged>() => Status::Crypto as i32,
+            _ if error.kind() == std::io::ErrorKind::InvalidData => Status::Usage as i32,
+            _ => Status::Io as i32,
+        }
+    } else if error.is::<DeviceError>() {
+        Status::Device as i32
+    } else if error.is::<ed25519_dalek::SignatureError>() {
+        Status::Crypto as i32
+    } else {
+        1
+    }
+}
+
+/// The name of the class of failure with exit status `code`, as reported by
+/// `--json-errors`
+pub(crate) fn kind(code: i32) -> &'static str {
+    match code {
+        c if c == Status::Usage as i32 => "usage",
+        c if c == Status::Device as i32 => "device",
+        c if c == Status::Crypto as i32 => "crypto",
+        c if c == Status::Io as i32 => "io",
+        _ => "other",
+    }
+}
+
+/// Print `error` to stderr, as JSON if `json` is set, and return its exit
+/// status
+pub(crate) fn report(error: &(dyn std::error::Error + 'static), json: bool) -> i32 {
+    let code = exit_code(error);
+    if json {
+        eprintln!(
+            "{}",
+            serde_json::json!({
+                "error": error.to_string(),
+                "kind": kind(code),
+                "code": code,
+            })
+        )
+    } else {
+        eprintln!("Error: {}", error)
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Error as IoError, ErrorKind};
+
+    #[test]
+    fn classifies_errors() {
+        let code = |e: Error| exit_code(&*e);
+        assert_eq!(code("anything".into()), 1);
+        assert_eq!(code(device("no device")), 3);
+        assert_eq!(code(crypto("bad signature")), 4);
+        assert_eq!(code(IoError::new(ErrorKind::NotFound, "gone").into()), 5);
+        assert_eq!(code(IoError::new(ErrorKind::InvalidData, "bad line").into()), 2);
+        assert_eq!(kind(code(device("no device"))), "device");
+        assert_eq!(kind(1), "other");
+        assert_eq!(
+            code(IoError::new(ErrorKind::InvalidData, crypto("Allowlist forged!")).into()),
+            4
+        );
+        assert_eq!(code(IoError::new(ErrorKind::InvalidData, Forged).into()), 4);
+        assert_eq!(code(DeviceError::Unusable.into()), 3);
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of ledgeracio.
+//
+// ledgeracio is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ledgeracio is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ledgeracio.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Calls and storage items that substrate-subxt does not provide
+
+use codec::{Compact, Decode, Encode, Input};
+use std::{convert::TryFrom, marker::PhantomData};
+use substrate_subxt::{sp_runtime::Perbill,
+                      staking::{Staking, StakingEventsDecoder as _},
+                      Call, Store};
+
+const MODULE: &str = "Staking";
+
+/// Schedule part of the active stake for unbonding
+#[derive(Clone, Debug, PartialEq, Call, Encode)]
+pub struct UnbondCall<T: Staking> {
+    /// The amount to unbond, in planck
+    pub value: Compact<u128>,
+    /// Marker for the runtime
+    pub _runtime: PhantomData<T>,
+}
+
+/// Add free funds of the stash to its stake
+#[derive(Clone, Debug, PartialEq, Call, Encode)]
+pub struct BondExtraCall<T: Staking> {
+    /// The amount to add, in planck
+    pub max_additional: Compact<u128>,
+    /// Marker for the runtime
+    pub _runtime: PhantomData<T>,
+}
+
+/// Pay out the rewards of a validator and its nominators for one era
+#[derive(Clone, Debug, PartialEq, Call, Encode)]
+pub struct PayoutStakersCall<T: Staking> {
+    /// The stash of the validator
+    pub validator_stash: T::AccountId,
+    /// The era to pay out
+    pub era: u32,
+}
+
+/// The era that is currently being rewarded
+#[derive(Clone, Debug, PartialEq, Eq, Decode)]
+pub struct ActiveEraInfo {
+    /// The index of the era
+    pub index: u32,
+    /// When the era started, in milliseconds since the Unix epoch
+    pub start: Option<u64>,
+}
+
+/// The active era
+#[derive(Clone, Debug, PartialEq, Eq, Encode, Store)]
+pub struct ActiveEraStore<T: Staking> {
+    /// Marker for the runtime
+    #[store(returns = ActiveEraInfo)]
+    pub _runtime: PhantomData<T>,
+}
+
+/// Withdraw the funds of the stash that have finished unbonding
+#[derive(Clone, Debug, PartialEq, Call, Encode)]
+pub struct WithdrawUnbondedCall<T: Staking> {
+    /// The number of slashing spans of the stash.  The call fails if this is
+    /// too small.
+    pub num_slashing_spans: u32,
+    /// Marker for the runtime
+    pub _runtime: PhantomData<T>,
+}
+
+/// The slashing spans of a stash
+#[derive(Clone, Debug, PartialEq, Eq, Decode)]
+pub struct SlashingSpans {
+    /// The index of the current span
+    pub span_index: u32,
+    /// The era in which the current span started
+    pub last_start: u32,
+    /// The last era in which the stash was slashed
+    pub last_nonzero_slash: u32,
+    /// The lengths of the prior spans, most recent first
+    pub prior: Vec<u32>,
+}
+
+impl SlashingSpans {
+    /// The number of spans, counting the current one.  This is the value that
+    /// `withdraw_unbonded` needs.
+    pub fn count(&self) -> u32 {
+        u32::try_from(self.prior.len())
+            .ok()
+            .and_then(|prior| prior.checked_add(1))
+            .unwrap_or(u32::MAX)
+    }
+}
+
+/// The slashing spans of a stash, if it has ever been slashed
+#[derive(Clone, Debug, PartialEq, Eq, Encode, Store)]
+pub struct SlashingSpansStore<T: Staking> {
+    /// The stash account
+    #[store(returns = SlashingSpans)]
+    pub stash: T::AccountId,
+}
+
+/// The preferences of a validator.  Newer runtimes also store whether the
+/// validator is blocking new nominations; older ones do not.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Prefs {
+    /// The commission of the validator
+    pub commission: Perbill,
+    /// Whether the validator is blocking new nominations, if the runtime
+    /// stores it
+    pub blocked: Option<bool>,
+}
+
+impl Decode for Prefs {
+    fn decode<I: Input>(input: &mut I) -> Result<Self, codec::Error> {
+        let commission = <Compact<Perbill>>::decode(input)?.0;
+        let blocked = match input.remaining_len()? {
+            Some(len) if len > 0 => Some(bool::decode(input)?),
+            _ => None,
+        };
+        Ok(Self {
+            commission,
+            blocked,
+        })
+    }
+}
+
+/// The preferences of a validator, decoded as [`Prefs`]
+#[derive(Clone, Debug, PartialEq, Eq, Encode, Store)]
+pub struct ValidatorsStore<T: Staking> {
+    /// The stash account
+    #[store(returns = Prefs)]
+    pub stash: T::AccountId,
+}
+
+/// Storage items in the session module
+pub mod session {
+    use codec::Encode;
+    use std::marker::PhantomData;
+    use substrate_subxt::{system::System, Store};
+
+    const MODULE: &str = "Session";
+
+    /// The stash accounts of the validators in the current session
+    #[derive(Clone, Debug, PartialEq, Eq, Encode, Store)]
+    pub struct ValidatorsStore<T: System> {
+        /// Marker for the runtime
+        #[store(returns = Vec<T::AccountId>)]
+        pub _runtime: PhantomData<T>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_the_current_span() {
+        let spans = SlashingSpans {
+            span_index: 3,
+            last_start: 10,
+            last_nonzero_slash: 8,
+            prior: vec![4, 2],
+        };
+        assert_eq!(spans.count(), 3);
+    }
+
+    #[test]
+    fn prefs_decode_with_and_without_blocked() {
+        let commission = Perbill::from_parts(50_000_000);
+        let mut old = Compact(commission).encode();
+        let prefs = Prefs::decode(&mut &*old).unwrap();
+        assert_eq!(prefs.commission, commission);
+        assert_eq!(prefs.blocked, None);
+        old.push(1);
+        assert_eq!(Prefs::decode(&mut &*old).unwrap().blocked, Some(true));
+    }
+}
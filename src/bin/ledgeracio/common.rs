@@ -16,11 +16,17 @@
 
 //! Utilities shared by both validator and nominator code
 
-use super::{AccountId, AccountType, Error, LedgeracioPath};
-use substrate_subxt::{sp_core::crypto::{Ss58AddressFormat, Ss58Codec},
-                      staking::{LedgerStore, StakingLedger, ValidatorsStore},
+use super::{calls::{ActiveEraStore, BondExtraCall, PayoutStakersCall, SlashingSpans,
+                   SlashingSpansStore, UnbondCall, ValidatorsStore, WithdrawUnbondedCall},
+            AccountId, AccountType, Error, LedgeracioPath, OutputFormat, Wait};
+use ledgeracio::HardSigner;
+use serde_json::{json, Value};
+use std::marker::PhantomData;
+use substrate_subxt::{sp_core::{crypto::{Ss58AddressFormat, Ss58Codec},
+                                H256},
+                      staking::{BondedStore, ChillCall, LedgerStore, StakingLedger, UnlockChunk},
                       system::AccountStoreExt,
-                      Client, KusamaRuntime, Signer, SystemProperties};
+                      Call, Client, KusamaRuntime, Signer as _};
 
 pub(crate) async fn fetch_validators(
     client: &Client<KusamaRuntime>,
@@ -34,20 +40,18 @@ pub(crate) async fn fetch_validators(
     let mut v = vec![];
     if let Some(index) = index {
         let path = LedgeracioPath::new(network, account_type, index)?;
-        let signer = keystore.signer(path).await?;
-        return Ok(vec![signer.account_id().clone()])
+        return Ok(vec![keystore.account_id(&path).await?])
     }
     let mut index = 0_u32;
     loop {
         index += 1;
         assert!(index > 0);
         let path = LedgeracioPath::new(network, account_type, index)?;
-        let signer = keystore.signer(path).await?;
-        let account_id = signer.account_id();
-        if client.account(account_id, None).await?.data.free == 0 {
+        let account_id = keystore.account_id(&path).await?;
+        if client.account(&account_id, None).await?.data.free == 0 {
             return Ok(v)
         }
-        v.push(account_id.clone())
+        v.push(account_id)
     }
 }
 
@@ -59,8 +63,13 @@ pub(crate) async fn display_validators(
     client: &Client<KusamaRuntime>,
     nominations: &[AccountId],
     network: Ss58AddressFormat,
+    format: OutputFormat,
 ) -> Result<(), Error> {
     for controller in nominations {
+        if format == OutputFormat::Json {
+            println!("{}", validator_json(client, controller, network).await?);
+            continue
+        }
         let store = LedgerStore {
             controller: controller.clone(),
         };
@@ -76,27 +85,14 @@ pub(crate) async fn display_validators(
                 unlocking,
                 claimed_rewards: _,
             }) => {
-                let SystemProperties {
-                    token_decimals,
-                    mut token_symbol,
-                    ..
-                } = client.properties().clone();
-                let mut good_symbol = true;
-                for i in token_symbol.bytes() {
-                    good_symbol &= i.is_ascii_uppercase()
-                }
-                if !good_symbol {
-                    token_symbol = "".to_owned()
-                }
                 println!(
-                    "    Validator account: {}\n    Stash balance: {} {sym}\n    Amount at stake: \
-                     {} {sym}\nEras with unclaimed payouts: {:?}\n    Amount unlocking: {:?}",
+                    "    Validator account: {}\n    Stash balance: {}\n    Amount at stake: \
+                     {}\nEras with unclaimed payouts: {:?}\n    Amount unlocking: {}",
                     stash.to_ss58check_with_version(network),
-                    pad(token_decimals, total),
-                    pad(token_decimals, active),
+                    format_balance(total, network),
+                    format_balance(active, network),
                     super::payouts::display_payouts(controller.clone(), client).await?,
-                    unlocking,
-                    sym = token_symbol
+                    format_unlocking(&unlocking, network),
                 );
                 let store = ValidatorsStore {
                     stash: stash.clone(),
@@ -106,10 +102,16 @@ pub(crate) async fn display_validators(
                         "    validator {} has no preferences ― it is probably inactive\n",
                         stash.to_ss58check_with_version(network)
                     ),
-                    Some(prefs) => println!(
-                        "    Commission: {}%\n",
-                        pad(9, u128::from(prefs.commission.deconstruct()) * 100)
-                    ),
+                    Some(prefs) => {
+                        println!(
+                            "    Commission: {}%",
+                            pad(9, u128::from(prefs.commission.deconstruct()) * 100)
+                        );
+                        if prefs.blocked == Some(true) {
+                            println!("    Blocking new nominations")
+                        }
+                        println!()
+                    }
                 }
             }
         }
@@ -117,6 +119,286 @@ pub(crate) async fn display_validators(
     Ok(())
 }
 
+/// Sign and submit `call`, then wait for it as `wait` says
+pub(crate) async fn submit<C: Call<KusamaRuntime> + Send + Sync>(
+    client: &Client<KusamaRuntime>,
+    call: C,
+    signer: &HardSigner,
+    wait: Wait,
+) -> Result<Option<H256>, Error> {
+    if wait == Wait::None {
+        return Ok(Some(client.submit(call, signer).await?))
+    }
+    let success = client
+        .watch(call, signer)
+        .await
+        .map_err(crate::dispatch_error)?;
+    crate::report(client, success, wait).await
+}
+
+/// Withdraw the unbonded funds of the stash controlled by `signer`.  Unless
+/// `num_slashing_spans` is given, it is read from the chain.
+pub(crate) async fn withdraw_unbonded(
+    client: &Client<KusamaRuntime>,
+    signer: &HardSigner,
+    num_slashing_spans: Option<u32>,
+    wait: Wait,
+) -> Result<Option<H256>, Error> {
+    let num_slashing_spans = match num_slashing_spans {
+        Some(count) => count,
+        None => {
+            let controller = signer.account_id().clone();
+            let ledger = client
+                .fetch(&LedgerStore { controller }, None)
+                .await?
+                .ok_or("This account is not a controller")?;
+            let store = SlashingSpansStore {
+                stash: ledger.stash,
+            };
+            client
+                .fetch(&store, None)
+                .await?
+                .map_or(0, SlashingSpans::count)
+        }
+    };
+    let call = WithdrawUnbondedCall {
+        num_slashing_spans,
+        _runtime: PhantomData,
+    };
+    submit(client, call, signer, wait).await
+}
+
+/// Chill the account controlled by `signer`, and then unbond `amount` (by
+/// default, all of the active stake).  Fails if `amount` is more than the
+/// active stake.  The Ledgeracio app cannot check calls nested in a batch, so
+/// these are two extrinsics, and the unbond is only sent once the chill has
+/// been included.
+pub(crate) async fn chill_then_unbond(
+    client: &Client<KusamaRuntime>,
+    signer: &HardSigner,
+    network: Ss58AddressFormat,
+    amount: Option<u128>,
+    wait: Wait,
+) -> Result<Option<H256>, Error> {
+    let controller = signer.account_id().clone();
+    let ledger = client
+        .fetch(&LedgerStore { controller }, None)
+        .await?
+        .ok_or("This account is not a controller")?;
+    let amount = amount.unwrap_or(ledger.active);
+    if amount == 0 {
+        return Err("Nothing to unbond".into())
+    }
+    if amount > ledger.active {
+        return Err(format!(
+            "Cannot unbond {}: only {} is active",
+            format_balance(amount, network),
+            format_balance(ledger.active, network)
+        )
+        .into())
+    }
+    let chill = ChillCall {
+        _runtime: PhantomData,
+    };
+    if let Some(hash) = submit(client, chill, signer, before_next(wait)).await? {
+        println!("Chill transaction hash: {:?}", hash)
+    }
+    let call = UnbondCall {
+        value: amount.into(),
+        _runtime: PhantomData,
+    };
+    submit(client, call, signer, wait)
+        .await
+        .map_err(|e| format!("The account is chilled, but is still bonded: {}", e).into())
+}
+
+/// How long to wait for an extrinsic that is followed by another from the same
+/// signer.  The nonce of the next one is read from the chain, so this must be
+/// at least [`Wait::Included`].
+fn before_next(wait: Wait) -> Wait {
+    if wait == Wait::None {
+        Wait::Included
+    } else {
+        wait
+    }
+}
+
+/// Bond `amount` more of the free balance of the stash `signer`.  If `amount`
+/// is `max`, bond all of the free balance except `keep_alive`.
+pub(crate) async fn bond_extra(
+    client: &Client<KusamaRuntime>,
+    signer: &HardSigner,
+    network: Ss58AddressFormat,
+    amount: &str,
+    keep_alive: &str,
+    wait: Wait,
+) -> Result<Option<H256>, Error> {
+    let stash = signer.account_id().clone();
+    let bonded = BondedStore {
+        stash: stash.clone(),
+    };
+    if client.fetch(&bonded, None).await?.is_none() {
+        return Err("This account is not a stash.  bond-extra must be signed by the stash."
+            .to_owned()
+            .into())
+    }
+    let amount = if amount == "max" {
+        let keep_alive = parse_balance(keep_alive, network)?;
+        let free = client.account(&stash, None).await?.data.free;
+        match free.checked_sub(keep_alive) {
+            Some(amount) if amount > 0 => amount,
+            _ => {
+                return Err(format!(
+                    "The free balance of {} is not more than the --keep-alive reserve of {}",
+                    format_balance(free, network),
+                    format_balance(keep_alive, network)
+                )
+                .into())
+            }
+        }
+    } else {
+        parse_balance(amount, network)?
+    };
+    println!("Bonding {} more", format_balance(amount, network));
+    let call = BondExtraCall {
+        max_additional: amount.into(),
+        _runtime: PhantomData,
+    };
+    submit(client, call, signer, wait).await
+}
+
+/// Pay out every era of the last `depth` (by default, all that the chain
+/// keeps) in which the validator with stash `validator` earned points but has
+/// not been paid.  The Ledgeracio app cannot check calls nested in a batch, so
+/// each era is its own extrinsic.  Stops at the first that fails.
+pub(crate) async fn payout_stakers(
+    client: &Client<KusamaRuntime>,
+    signer: &HardSigner,
+    validator: AccountId,
+    depth: Option<u32>,
+    wait: Wait,
+) -> Result<Option<H256>, Error> {
+    let bonded = BondedStore {
+        stash: validator.clone(),
+    };
+    let controller = client
+        .fetch(&bonded, None)
+        .await?
+        .ok_or("That account is not a validator stash")?;
+    let active_era = client
+        .fetch(
+            &ActiveEraStore {
+                _runtime: PhantomData,
+            },
+            None,
+        )
+        .await?
+        .ok_or("The chain has no active era")?
+        .index;
+    let first = depth.map_or(0, |depth| active_era.saturating_sub(depth));
+    let mut eras = super::payouts::display_payouts(controller, client).await?;
+    eras.retain(|&era| era >= first && era < active_era);
+    eras.sort_unstable();
+    let (&last, rest) = match eras.split_last() {
+        Some(split) => split,
+        None => {
+            println!("No eras to pay out");
+            return Ok(None)
+        }
+    };
+    println!("Paying out eras {:?}", eras);
+    for &era in rest {
+        let call = PayoutStakersCall {
+            validator_stash: validator.clone(),
+            era,
+        };
+        if let Some(hash) = submit(client, call, signer, before_next(wait)).await? {
+            println!("Era {} transaction hash: {:?}", era, hash)
+        }
+    }
+    let call = PayoutStakersCall {
+        validator_stash: validator,
+        era: last,
+    };
+    submit(client, call, signer, wait).await
+}
+
+/// Parse `amount`, a number of whole tokens of `network`, into planck.
+/// Networks whose decimals are not known take an amount in planck.
+pub fn parse_balance(amount: &str, network: Ss58AddressFormat) -> Result<u128, Error> {
+    let decimals: usize = match network {
+        Ss58AddressFormat::PolkadotAccount => 10,
+        Ss58AddressFormat::KusamaAccount => 12,
+        _ => 0,
+    };
+    let (whole, fraction) = match amount.find('.') {
+        Some(dot) if decimals > 0 => (&amount[..dot], &amount[dot + 1..]),
+        _ => (amount, ""),
+    };
+    if fraction.len() > decimals {
+        return Err(format!("{} has more than {} decimal places", amount, decimals).into())
+    }
+    let is_number = |s: &str| s.bytes().all(|i| i.is_ascii_digit());
+    if (whole.is_empty() && fraction.is_empty()) || !is_number(whole) || !is_number(fraction) {
+        return Err(format!("{} is not a valid amount", amount).into())
+    }
+    format!("{}{:0<width$}", whole, fraction, width = decimals)
+        .parse()
+        .map_err(|_| format!("{} is too large", amount).into())
+}
+
+/// The JSON form of the validator with controller `controller`.  `ledger` is
+/// `null` if there is no such validator.
+async fn validator_json(
+    client: &Client<KusamaRuntime>,
+    controller: &AccountId,
+    network: Ss58AddressFormat,
+) -> Result<Value, Error> {
+    let store = LedgerStore {
+        controller: controller.clone(),
+    };
+    let ledger = match client.fetch(&store, None).await? {
+        None => {
+            return Ok(json!({
+                "controller": controller.to_ss58check_with_version(network),
+                "ledger": null,
+            }))
+        }
+        Some(ledger) => ledger,
+    };
+    let unclaimed_eras = super::payouts::display_payouts(controller.clone(), client).await?;
+    let store = ValidatorsStore {
+        stash: ledger.stash.clone(),
+    };
+    let prefs = client.fetch(&store, None).await?;
+    Ok(json!({
+        "controller": controller.to_ss58check_with_version(network),
+        "ledger": ledger_json(&ledger, network),
+        "unclaimed_eras": unclaimed_eras,
+        "commission_ppb": prefs.as_ref().map(|prefs| prefs.commission.deconstruct()),
+        "blocked": prefs.and_then(|prefs| prefs.blocked),
+    }))
+}
+
+/// The JSON form of a staking ledger.  Balances are strings of planck, so that
+/// they survive parsers that read every number as a double.
+pub(crate) fn ledger_json(
+    ledger: &StakingLedger<AccountId, u128>,
+    network: Ss58AddressFormat,
+) -> Value {
+    let unlocking: Vec<Value> = ledger
+        .unlocking
+        .iter()
+        .map(|chunk| json!({ "value": chunk.value.to_string(), "era": chunk.era }))
+        .collect();
+    json!({
+        "stash": ledger.stash.to_ss58check_with_version(network),
+        "total": ledger.total.to_string(),
+        "active": ledger.active.to_string(),
+        "unlocking": unlocking,
+    })
+}
+
 pub fn pad(mut zeros: u8, value: u128) -> String {
     if value == 0 {
         return "0".to_owned()
@@ -143,6 +425,29 @@ pub fn pad(mut zeros: u8, value: u128) -> String {
     value
 }
 
+/// Format `amount`, in planck, as a number of whole tokens of `network`.
+/// Networks whose decimals are not known are shown in planck.
+pub fn format_balance(amount: u128, network: Ss58AddressFormat) -> String {
+    match network {
+        Ss58AddressFormat::PolkadotAccount => format!("{} DOT", pad(10, amount)),
+        Ss58AddressFormat::KusamaAccount => format!("{} KSM", pad(12, amount)),
+        _ => format!("{} planck (token decimals unknown for this network)", amount),
+    }
+}
+
+/// Format the chunks of a staking ledger that are unlocking, with the era in
+/// which each one unlocks.
+pub fn format_unlocking(chunks: &[UnlockChunk<u128>], network: Ss58AddressFormat) -> String {
+    if chunks.is_empty() {
+        return "none".to_owned()
+    }
+    chunks
+        .iter()
+        .map(|chunk| format!("{} in era {}", format_balance(chunk.value, network), chunk.era))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 pub fn parse_ppb(data: &str) -> Result<u32, Error> {
     if data.len() > 11 {
         Err("Commission too long.  Check for excess trailing zeroes."
@@ -189,6 +494,38 @@ mod tests {
         assert_eq!(pad(3, 10010), "10.01".to_owned());
     }
 
+    #[test]
+    fn balances_use_network_decimals() {
+        use Ss58AddressFormat::{KusamaAccount, PolkadotAccount, SubstrateAccount};
+        assert_eq!(format_balance(0, PolkadotAccount), "0 DOT");
+        assert_eq!(format_balance(15_000_000_000, PolkadotAccount), "1.5 DOT");
+        assert_eq!(format_balance(1, KusamaAccount), "0.000000000001 KSM");
+        assert_eq!(
+            format_balance(12, SubstrateAccount),
+            "12 planck (token decimals unknown for this network)"
+        );
+        let chunks = [UnlockChunk {
+            value: 2_000_000_000_000,
+            era: 7,
+        }];
+        assert_eq!(format_unlocking(&chunks, KusamaAccount), "2 KSM in era 7");
+        assert_eq!(format_unlocking(&[], KusamaAccount), "none");
+    }
+
+    #[test]
+    fn balances_parse_with_network_decimals() {
+        use Ss58AddressFormat::{KusamaAccount, PolkadotAccount, SubstrateAccount};
+        assert_eq!(parse_balance("1.5", PolkadotAccount).unwrap(), 15_000_000_000);
+        assert_eq!(parse_balance("2", KusamaAccount).unwrap(), 2_000_000_000_000);
+        assert_eq!(parse_balance("0.000000000001", KusamaAccount).unwrap(), 1);
+        assert_eq!(parse_balance("12", SubstrateAccount).unwrap(), 12);
+        assert!(parse_balance("1.5", SubstrateAccount).is_err());
+        assert!(parse_balance("0.00000000001", PolkadotAccount).is_err());
+        assert!(parse_balance("1e3", PolkadotAccount).is_err());
+        assert!(parse_balance("-1", PolkadotAccount).is_err());
+        assert!(parse_balance(".", PolkadotAccount).is_err());
+    }
+
     #[test]
     fn parse_ppb_works() {
         assert_eq!(parse_ppb("1.000000000").unwrap(), 1_000_000_000);
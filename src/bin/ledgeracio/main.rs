@@ -20,25 +20,28 @@
 #![allow(clippy::non_ascii_literal)]
 #![forbid(unsafe_code)]
 
+mod calls;
 mod common;
 mod nominator;
 mod payouts;
 mod validator;
 
-use futures::future::TryFutureExt;
-use ledgeracio::{get_network, AccountType, Error, HardSigner, HardStore, LedgeracioPath};
+use ledgeracio::{get_network, AccountType, Error, HardStore, LedgeracioPath, OutputFormat};
 
 #[cfg(not(unix))]
 compile_error!("Only *nix-like platforms are supported");
 
 use common::AddressSource;
 use sp_core::crypto::AccountId32 as AccountId;
-use std::{fmt::Debug, future::Future, pin::Pin};
-use structopt::StructOpt;
+use std::{convert::TryFrom, fmt::Debug, future::Future, pin::Pin};
+use structopt::{clap::ErrorKind, StructOpt};
 use substrate_subxt::{sp_core,
-                      sp_core::crypto::{Ss58AddressFormat, Ss58Codec},
+                      sp_core::{crypto::{Ss58AddressFormat, Ss58Codec},
+                                H256},
+                      sp_runtime::traits::Header as _,
                       staking::RewardDestination,
-                      Client, ClientBuilder, Signer};
+                      Client, ClientBuilder, Error as SubxtError, ExtrinsicSuccess, ModuleError,
+                      RuntimeError};
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "Ledgeracio", about = "Ledger CLI for staking")]
@@ -46,12 +49,56 @@ struct Ledgeracio {
     /// Dry run.  Do not execute the operation.
     #[structopt(short = "n", long)]
     dry_run: bool,
-    /// RPC host
-    #[structopt(short, long)]
+    /// RPC host, such as `ws://127.0.0.1:9944`.  This overrides the default for
+    /// the network, and the SS58 prefix of the chain must match `--network`.
+    /// A comma-separated list of hosts is tried in order, and the first that
+    /// can be reached and is on the right chain is used for everything.
+    #[structopt(short, long, alias = "ws-url")]
     host: Option<String>,
-    /// Network
-    #[structopt(long, parse(try_from_str = get_network))]
-    network: Ss58AddressFormat,
+    /// Network, or `auto` to use the SS58 prefix of the chain at `--host`.
+    /// `auto` connects to the node before running any command.
+    #[structopt(long)]
+    network: Network,
+    /// Give up waiting for the Ledger device after this many seconds, except
+    /// while it waits for the user to confirm.  By default, wait forever.
+    #[structopt(long)]
+    timeout: Option<u64>,
+    /// The output format of the `show` and `show-address` commands: `text`
+    /// (the default) or `json`.  JSON output is one object per account, on a
+    /// line of its own.  Balances in it are strings, in planck.
+    #[structopt(long, default_value = "text")]
+    format: OutputFormat,
+    /// Retry connecting this many times, waiting 1, 2, 4, … (at most 60)
+    /// seconds in between.  Each retry tries every host again.  Queries are
+    /// not retried once connected, and transactions are never resubmitted.
+    #[structopt(long, default_value = "0")]
+    retries: u32,
+    /// After submitting a transaction, wait until it is `included` in a block
+    /// or that block is `finalized`, and print the block hash and events.  The
+    /// default, `none`, returns as soon as the node accepts the transaction.
+    #[structopt(long, default_value = "none")]
+    wait: Wait,
+    /// Do not show progress bars.  They are also hidden when stderr is not a
+    /// terminal.
+    #[structopt(short, long)]
+    quiet: bool,
+    /// Report failures on stderr as a JSON object with the message (`error`),
+    /// `kind` (`usage` for bad arguments, `interrupted` after Ctrl-C, or
+    /// `other`), and the exit status (`code`, 1 or 130).
+    /// Setting `LEDGERACIO_JSON` to a non-empty value does the same.
+    #[structopt(long = "json-errors")]
+    json_errors: bool,
+    /// Append a line of JSON to this file for every extrinsic signed, with
+    /// the time, the account ID, the call, the nonce, and the extrinsic hash.
+    /// If the line cannot be written, the extrinsic is not submitted.
+    #[structopt(long = "audit-log")]
+    audit_log: Option<std::path::PathBuf>,
+    /// Give up if the whole command takes longer than this many seconds,
+    /// including connecting, queries, and waiting for the device or for
+    /// `--wait`.  By default, there is no limit.  A transaction that was
+    /// already submitted may still be included.
+    #[structopt(long)]
+    deadline: Option<u64>,
     /// Subcommand
     #[structopt(subcommand)]
     cmd: Command,
@@ -62,17 +109,43 @@ async fn display_path(
     keystore: &HardStore,
     network: Ss58AddressFormat,
     index: u32,
+    on_device: bool,
 ) -> Result<(), Error> {
     if index == 0 {
         return Err("Index must not be zero".to_owned().into())
     }
     let path = LedgeracioPath::new(network, account_type, index)?;
-    let signer: HardSigner = keystore.signer(path).await?;
-    let account_id: &AccountId = signer.account_id();
+    let account_id: AccountId = if on_device {
+        eprintln!("Check that the address on the device matches the one printed here.");
+        keystore.show_address(&path).await?
+    } else {
+        keystore.account_id(&path).await?
+    };
     println!("{}", account_id.to_ss58check_with_version(network));
     Ok(())
 }
 
+async fn display_range(
+    account_type: AccountType,
+    keystore: &HardStore,
+    network: Ss58AddressFormat,
+    start: u32,
+    count: u32,
+) -> Result<(), Error> {
+    if start == 0 {
+        return Err("Index must not be zero".to_owned().into())
+    }
+    let end = start
+        .checked_add(count)
+        .ok_or_else(|| "Index range overflows".to_owned())?;
+    for index in start..end {
+        let path = LedgeracioPath::new(network, account_type, index)?;
+        let account_id = keystore.account_id(&path).await?;
+        println!("{}\t{}", index, account_id.to_ss58check_with_version(network));
+    }
+    Ok(())
+}
+
 #[derive(StructOpt, Debug)]
 enum Command {
     /// Nominator operations
@@ -83,50 +156,346 @@ enum Command {
     Metadata,
     /// Display the chain properties
     Properties,
+    /// List the validators of the current session, with their commission
+    Validators(validator::List),
+    /// Display the addresses at a range of indices, without any prompts on the
+    /// device.
+    Addresses {
+        /// The type of account: `nominator` or `validator`
+        #[structopt(long = "account-type", default_value = "nominator")]
+        account_type: AccountType,
+        /// The first index to display
+        #[structopt(long, default_value = "1")]
+        start: u32,
+        /// The number of addresses to display
+        #[structopt(long, default_value = "20")]
+        count: u32,
+    },
+    /// Print the address of an account, without signing anything or any
+    /// prompt on the device.  This is the same as `nominator address` or
+    /// `validator address`.
+    Pubkey {
+        /// The type of account: `nominator` or `validator`
+        #[structopt(long = "account-type", default_value = "nominator")]
+        account_type: AccountType,
+        /// The index of the account
+        #[structopt(long)]
+        index: u32,
+    },
+    /// Print the BIP32 derivation path used for an account, such as
+    /// `m/44'/354'/0'/0'/1'`.  The device is not needed.
+    Path {
+        /// The type of account: `nominator` or `validator`
+        #[structopt(long = "account-type", default_value = "nominator")]
+        account_type: AccountType,
+        /// The index of the account
+        #[structopt(long)]
+        index: u32,
+    },
 }
 
 type Runtime = substrate_subxt::KusamaRuntime;
 
-fn parse_reward_destination(arg: &str) -> Result<RewardDestination<AccountId>, Error> {
+/// How long to wait after submitting a transaction
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Wait {
+    /// Return as soon as the node accepts the transaction
+    None,
+    /// Wait until the transaction is included in a block
+    Included,
+    /// Wait until that block is finalized
+    Finalized,
+}
+
+impl std::str::FromStr for Wait {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "included" => Ok(Self::Included),
+            "finalized" => Ok(Self::Finalized),
+            _ => Err("Wait mode must be `none`, `included`, or `finalized`"),
+        }
+    }
+}
+
+/// Convert an error from watching a transaction into one that names the
+/// module and error, such as `Staking.InsufficientValue`, if the transaction
+/// failed on chain.
+fn dispatch_error(error: substrate_subxt::Error) -> Error {
+    match error {
+        SubxtError::Runtime(RuntimeError::Module(ModuleError { module, error })) => {
+            format!("Transaction failed: {}.{}", module, error).into()
+        }
+        SubxtError::Runtime(error) => format!("Transaction failed: {}", error).into(),
+        error => error.into(),
+    }
+}
+
+/// Print the block and events of a transaction that has been included in a
+/// block.  If `wait` is [`Wait::Finalized`], first wait for that block to be
+/// finalized.
+async fn report(
+    client: &Client<Runtime>,
+    success: ExtrinsicSuccess<Runtime>,
+    wait: Wait,
+) -> Result<Option<H256>, Error> {
+    if wait == Wait::Finalized {
+        wait_for_finality(client, success.block).await?;
+        println!("Finalized in block {:?}", success.block);
+    } else {
+        println!("Included in block {:?}", success.block);
+    }
+    for event in &success.events {
+        println!("Event: {}.{}", event.module, event.variant);
+    }
+    Ok(Some(success.extrinsic))
+}
+
+/// Wait until `block` is finalized.  Fails if a different block at the same
+/// height is finalized instead.
+async fn wait_for_finality(client: &Client<Runtime>, block: H256) -> Result<(), Error> {
+    let missing = "The node does not have the block with the transaction";
+    let number = *client.header(Some(block)).await?.ok_or(missing)?.number();
+    loop {
+        let finalized = client.finalized_head().await?;
+        let mut header = client.header(Some(finalized)).await?.ok_or(missing)?;
+        if *header.number() >= number {
+            while *header.number() > number {
+                header = client
+                    .header(Some(*header.parent_hash()))
+                    .await?
+                    .ok_or(missing)?;
+            }
+            return if header.hash() == block {
+                Ok(())
+            } else {
+                Err(format!(
+                    "Block {:?} was finalized instead of {:?}.  The transaction is not final; \
+                     check whether it was included elsewhere before resubmitting it.",
+                    header.hash(),
+                    block
+                )
+                .into())
+            }
+        }
+        async_std::task::sleep(std::time::Duration::from_secs(2)).await
+    }
+}
+
+/// A reward destination, and the address it was parsed from with its SS58
+/// prefix, if it is an account
+type Payee = (RewardDestination<AccountId>, Option<(String, u8)>);
+
+fn parse_reward_destination(arg: &str) -> Result<Payee, Error> {
     Ok(match &*arg.to_ascii_lowercase() {
-        "staked" => RewardDestination::Staked,
-        "stash" => RewardDestination::Stash,
-        "controller" => RewardDestination::Controller,
+        "staked" => (RewardDestination::Staked, None),
+        "stash" => (RewardDestination::Stash, None),
+        "controller" => (RewardDestination::Controller, None),
+        lower if lower.starts_with("account:") => {
+            let address = &arg["account:".len()..];
+            let (account, prefix) = ledgeracio::parse_address(address)?;
+            (
+                RewardDestination::Account(account),
+                Some((address.to_owned(), prefix)),
+            )
+        }
         _ => {
-            return Err("Arbitrary reward destinations not supported"
-                .to_owned()
-                .into())
+            return Err(
+                "Reward destination must be `Staked`, `Stash`, `Controller`, or \
+                 `Account:<address>`"
+                    .to_owned()
+                    .into(),
+            )
         }
     })
 }
 
-async fn inner_main() -> Result<(), Error> {
+/// The value of `--network`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Network {
+    /// Use the SS58 prefix of the chain at `--host`
+    Auto,
+    /// A network given by name or SS58 prefix
+    Named(Ss58AddressFormat),
+}
+
+impl std::str::FromStr for Network {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            Ok(Self::Auto)
+        } else {
+            get_network(s).map(Self::Named)
+        }
+    }
+}
+
+/// Check that the address of `payee`, if any, is for `network`
+fn check_payee(
+    (destination, address): Payee,
+    network: Ss58AddressFormat,
+) -> Result<RewardDestination<AccountId>, Error> {
+    if let Some((address, prefix)) = address {
+        ledgeracio::validate_network(&address, prefix, network)?
+    }
+    Ok(destination)
+}
+
+/// Connect to the first of `hosts` that can be reached, retrying the whole
+/// list up to `retries` times with exponential backoff.  Nothing has been sent
+/// to the chain yet, so this is always safe to retry.  If `check_prefix` is
+/// set, a node is skipped unless its chain uses the SS58 prefix of `network`.
+/// If `network` is `None`, a node is skipped unless it declares a prefix.
+async fn connect(
+    hosts: &[String],
+    network: Option<Ss58AddressFormat>,
+    check_prefix: bool,
+    retries: u32,
+) -> Result<Client<Runtime>, Error> {
+    let mut delay = std::time::Duration::from_secs(1);
+    let mut attempt = 0;
+    loop {
+        let mut last_error: Option<Error> = None;
+        let mut unreachable = false;
+        for host in hosts {
+            let client = match ClientBuilder::<Runtime>::new()
+                .set_url(host.clone())
+                .build()
+                .await
+            {
+                Ok(client) => client,
+                Err(e) => {
+                    eprintln!("Connecting to {} failed ({})", host, e);
+                    unreachable = true;
+                    last_error = Some(e.into());
+                    continue
+                }
+            };
+            let prefix = client.properties().ss58_format;
+            let problem = match network {
+                Some(network) if check_prefix && prefix != u8::from(network) => Some(format!(
+                    "The chain at {} uses SS58 prefix {}, but network {} uses prefix {}",
+                    host,
+                    prefix,
+                    String::from(network),
+                    u8::from(network)
+                )),
+                Some(_) => None,
+                None => match declares_ss58_format(host).await {
+                    Ok(true) => None,
+                    Ok(false) => Some(format!(
+                        "The chain at {} does not declare an SS58 prefix (ss58Format)",
+                        host
+                    )),
+                    Err(e) => Some(format!("Reading the properties of {} failed ({})", host, e)),
+                },
+            };
+            if let Some(message) = problem {
+                eprintln!("{}", message);
+                last_error = Some(message.into());
+                continue
+            }
+            return Ok(client)
+        }
+        let last_error = last_error.unwrap_or_else(|| "No RPC endpoint given".into());
+        // A node on the wrong chain will not change its mind, so only retry if
+        // some node could not be reached at all
+        if !unreachable || attempt >= retries {
+            return Err(last_error)
+        }
+        attempt += 1;
+        eprintln!("Retry {} of {} in {:?}", attempt, retries, delay);
+        async_std::task::sleep(delay).await;
+        delay = std::cmp::min(delay * 2, std::time::Duration::from_secs(60));
+    }
+}
+
+/// Whether the node at `host` declares an SS58 prefix in its chain properties.
+/// subxt uses prefix 0 if it does not, so this asks the node directly.
+async fn declares_ss58_format(host: &str) -> Result<bool, Error> {
+    let client = if host.starts_with("ws://") || host.starts_with("wss://") {
+        jsonrpsee::ws_client(host).await?
+    } else {
+        jsonrpsee::http_client(host)
+    };
+    let properties: serde_json::Value = client
+        .request("system_properties", jsonrpsee::common::Params::None)
+        .await?;
+    Ok(properties
+        .get("ss58Format")
+        .map_or(false, serde_json::Value::is_u64))
+}
+
+async fn inner_main(args: Ledgeracio) -> Result<(), Error> {
     env_logger::init();
     let Ledgeracio {
         dry_run,
         host,
         network,
+        timeout,
+        format,
+        retries,
+        wait,
+        quiet,
+        json_errors: _,
+        audit_log,
+        deadline: _,
         cmd,
-    } = Ledgeracio::from_args();
-    let host = match (host, network) {
-        (Some(host), _) => host,
-        (None, Ss58AddressFormat::KusamaAccount) => "wss://kusama-rpc.polkadot.io".into(),
-        (None, Ss58AddressFormat::PolkadotAccount) => "wss://rpc.polkadot.io".into(),
+    } = args;
+    let check_prefix = host.is_some();
+    let hosts: Vec<String> = match (host, network) {
+        (Some(host), _) => host
+            .split(',')
+            .map(str::trim)
+            .filter(|host| !host.is_empty())
+            .map(str::to_owned)
+            .collect(),
+        (None, Network::Named(Ss58AddressFormat::KusamaAccount)) => {
+            vec!["wss://kusama-rpc.polkadot.io".into()]
+        }
+        (None, Network::Named(Ss58AddressFormat::PolkadotAccount)) => {
+            vec!["wss://rpc.polkadot.io".into()]
+        }
+        (None, Network::Auto) => return Err("--network auto requires --host".into()),
         _ => return Err("Please supply an RPC endpoint".into()),
     };
-
-    let client = ClientBuilder::<Runtime>::new()
-        .set_url(host)
-        .build()
-        .map_err(From::from);
-    let client: Pin<Box<dyn Future<Output = Result<Client<Runtime>, _>>>> = Box::pin(client);
-    let keystore = || HardStore::new(network);
     if dry_run {
         return Ok(())
     }
+
+    let (network, client) = match network {
+        Network::Named(network) => {
+            let client =
+                async move { connect(&hosts, Some(network), check_prefix, retries).await };
+            let client: Pin<Box<dyn Future<Output = Result<Client<Runtime>, Error>>>> =
+                Box::pin(client);
+            (network, client)
+        }
+        Network::Auto => {
+            let client = connect(&hosts, None, false, retries).await?;
+            let prefix = client.properties().ss58_format;
+            let network = Ss58AddressFormat::try_from(prefix)
+                .unwrap_or_else(|()| Ss58AddressFormat::Custom(prefix));
+            eprintln!("Using network {} (SS58 prefix {})", String::from(network), prefix);
+            let client: Pin<Box<dyn Future<Output = Result<Client<Runtime>, Error>>>> =
+                Box::pin(async move { Ok(client) });
+            (network, client)
+        }
+    };
+    let timeout = timeout.map(std::time::Duration::from_secs);
+    let keystore = || {
+        HardStore::new(network).map(|store| {
+            store
+                .with_timeout(timeout)
+                .with_audit_log(audit_log.clone())
+        })
+    };
     if let Some(hash) = match cmd {
-        Command::Nominator(s) => nominator::main(s, client, network, keystore).await?,
-        Command::Validator(v) => validator::main(v, client, network, keystore).await?,
+        Command::Nominator(s) => nominator::main(s, client, network, format, wait, keystore).await?,
+        Command::Validator(v) => validator::main(v, client, network, format, wait, keystore).await?,
         Command::Metadata => {
             println!("{:#?}", client.await?.metadata());
             None
@@ -135,18 +504,115 @@ async fn inner_main() -> Result<(), Error> {
             println!("{:#?}", client.await?.properties());
             None
         }
+        Command::Validators(options) => {
+            validator::list(&client.await?, network, format, quiet, options).await?;
+            None
+        }
+        Command::Addresses {
+            account_type,
+            start,
+            count,
+        } => {
+            display_range(account_type, &keystore()?, network, start, count).await?;
+            None
+        }
+        Command::Pubkey {
+            account_type,
+            index,
+        } => {
+            display_path(account_type, &keystore()?, network, index, false).await?;
+            None
+        }
+        Command::Path {
+            account_type,
+            index,
+        } => {
+            if index == 0 {
+                return Err("Index must not be zero".to_owned().into())
+            }
+            println!("{}", LedgeracioPath::new(network, account_type, index)?);
+            None
+        }
     } {
         println!("Transaction hash: {:?}", hash);
     }
     Ok(())
 }
 
+/// Run `command` to completion, unless the user presses Ctrl-C.  Returns
+/// `None` if it was interrupted, after dropping the command, which closes its
+/// connection to the node and releases the Ledger device.  A command can only
+/// be dropped while it is waiting, so a second Ctrl-C exits at once.
+fn run<F: Future<Output = Result<(), Error>>>(command: F) -> Option<Result<(), Error>> {
+    use futures::{future::{select, Either},
+                  StreamExt};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    let (sender, mut interrupts) = futures::channel::mpsc::unbounded();
+    let interrupted = AtomicBool::new(false);
+    if let Err(e) = ctrlc::set_handler(move || {
+        if interrupted.swap(true, Ordering::SeqCst) {
+            std::process::exit(130)
+        }
+        let _ = sender.unbounded_send(());
+    }) {
+        log::warn!("Ctrl-C cannot be handled: {}", e)
+    }
+    async_std::task::block_on(async move {
+        match select(Box::pin(command), interrupts.next()).await {
+            Either::Left((result, _)) => Some(result),
+            Either::Right(_) => None,
+        }
+    })
+}
+
+/// Whether `LEDGERACIO_JSON` asks for errors to be reported as JSON
+fn json_errors_from_env() -> bool {
+    std::env::var_os("LEDGERACIO_JSON").map_or(false, |value| !value.is_empty())
+}
+
+/// Whether errors should be reported as JSON, even if the arguments do not
+/// parse
+fn json_errors_requested() -> bool {
+    json_errors_from_env() || std::env::args_os().any(|arg| arg == "--json-errors")
+}
+
 fn main() {
-    match async_std::task::block_on(inner_main()) {
-        Ok(()) => (),
-        Err(e) => {
+    let args = Ledgeracio::from_iter_safe(std::env::args_os()).unwrap_or_else(|e| {
+        match e.kind {
+            ErrorKind::HelpDisplayed | ErrorKind::VersionDisplayed => e.exit(),
+            _ if json_errors_requested() => {
+                let error = serde_json::json!({ "error": e.message, "kind": "usage", "code": 1 });
+                eprintln!("{}", error);
+                std::process::exit(1)
+            }
+            _ => e.exit(),
+        }
+    });
+    let json_errors = args.json_errors || json_errors_from_env();
+    let deadline = args.deadline.map(std::time::Duration::from_secs);
+    match run(ledgeracio::with_deadline(inner_main(args), deadline)) {
+        Some(Ok(())) => (),
+        Some(Err(e)) if json_errors => {
+            let error = serde_json::json!({ "error": e.to_string(), "kind": "other", "code": 1 });
+            eprintln!("{}", error);
+            std::process::exit(1)
+        }
+        Some(Err(e)) => {
             eprintln!("Error: {}", e);
             std::process::exit(1)
         }
+        None if json_errors => {
+            let error = serde_json::json!({
+                "error": "Interrupted",
+                "kind": "interrupted",
+                "code": 130,
+            });
+            eprintln!("{}", error);
+            std::process::exit(130)
+        }
+        None => {
+            eprintln!("Interrupted");
+            std::process::exit(130)
+        }
     }
 }
@@ -16,14 +16,21 @@
 
 //! Nominator commands
 
-use super::{parse_reward_destination, AccountType, Error, LedgeracioPath, StructOpt};
-use crate::common::pad;
+use super::{parse_reward_destination, AccountType, Error, LedgeracioPath, OutputFormat,
+            StructOpt, Wait};
+use crate::common::{format_balance, format_unlocking, ledger_json};
 use core::{future::Future, pin::Pin};
-use ledgeracio::{parse_address, validate_network};
+use ledgeracio::parse_address;
+use serde_json::{json, Value};
+use std::{collections::HashSet,
+          fs::File,
+          io::BufReader,
+          marker::PhantomData,
+          path::{Path, PathBuf}};
 use substrate_subxt::{sp_core::{crypto::{AccountId32 as AccountId, Ss58AddressFormat, Ss58Codec},
                                 H256},
-                      staking::{BondedStore, ChillCallExt, LedgerStore, NominateCallExt,
-                                PayeeStore, RewardDestination, SetPayeeCallExt},
+                      staking::{BondedStore, ChillCall, LedgerStore, NominateCall, PayeeStore,
+                                RewardDestination, SetPayeeCall},
                       Client, KusamaRuntime};
 
 #[derive(StructOpt, Debug)]
@@ -41,6 +48,15 @@ pub(crate) enum Nominator {
         index: u32,
         #[structopt(parse(try_from_str = parse_address))]
         set: Vec<(AccountId, u8)>,
+        /// A signed allowlist to check the set against before anything is sent
+        /// to the device.  It must be signed by the key uploaded to the device.
+        #[structopt(long)]
+        allowlist: Option<PathBuf>,
+        /// Nominate validators that are not in `--allowlist` anyway, with a
+        /// warning.  The device will still refuse them if they are not on its
+        /// own allowlist.
+        #[structopt(long, requires = "allowlist")]
+        force: bool,
     },
     /// Chill (announce intention to cease nomination)
     Chill { index: u32 },
@@ -49,18 +65,150 @@ pub(crate) enum Nominator {
     SetPayee {
         index: u32,
         #[structopt(parse(try_from_str = parse_reward_destination))]
-        target: RewardDestination<AccountId>,
+        target: super::Payee,
+    },
+    /// Chill, and then unbond some or all of the active stake
+    ChillThenUnbond {
+        index: u32,
+        /// The amount to unbond, in whole tokens.  By default, all of the
+        /// active stake is unbonded.
+        amount: Option<String>,
+    },
+    /// Bond more of the free balance of the stash
+    BondExtra {
+        index: u32,
+        /// The amount to bond, in whole tokens, or `max` for all of the free
+        /// balance except `--keep-alive`
+        amount: String,
+        /// With `max`, the amount to leave unbonded, in whole tokens
+        #[structopt(long, default_value = "1")]
+        keep_alive: String,
+    },
+    /// Pay out the unclaimed rewards of a validator, for every era in which it
+    /// earned points
+    PayoutStakers {
+        index: u32,
+        /// The stash of the validator
+        #[structopt(long, parse(try_from_str = parse_address))]
+        validator: (AccountId, u8),
+        /// Only pay out the last this many eras
+        #[structopt(long)]
+        depth: Option<u32>,
+    },
+    /// Withdraw the funds that have finished unbonding
+    WithdrawUnbonded {
+        index: u32,
+        /// The number of slashing spans of the stash.  By default, this is
+        /// read from the chain.
+        #[structopt(long)]
+        num_slashing_spans: Option<u32>,
     },
     /// Display the address of the given index
-    Address { index: u32 },
+    Address {
+        index: u32,
+        /// Also show the address on the Ledger screen, so that it can be
+        /// checked against the one printed.
+        #[structopt(long = "on-device")]
+        on_device: bool,
+    },
+}
+
+/// The members of `set` that are not in the signed allowlist at `path`,
+/// encoded for `network`.  Fails if the allowlist was not signed by the key
+/// uploaded to the device.
+async fn not_allowed(
+    keystore: &super::HardStore,
+    path: &Path,
+    network: Ss58AddressFormat,
+    set: &[AccountId],
+) -> Result<Vec<String>, Error> {
+    let allowlist = ledgeracio::allowlist::decode(BufReader::new(File::open(path)?), network)?;
+    let key = ed25519_dalek::PublicKey::from_bytes(&keystore.get_pubkey().await?)?;
+    allowlist.verify(&key)?;
+    let allowed: HashSet<&str> = allowlist.addresses.iter().map(|s| &**s).collect();
+    Ok(set
+        .iter()
+        .map(|address| address.to_ss58check_with_version(network))
+        .filter(|address| !allowed.contains(&**address))
+        .collect())
+}
+
+/// The JSON form of a reward destination
+fn payee_json(payee: &RewardDestination<AccountId>, network: Ss58AddressFormat) -> Value {
+    match payee {
+        RewardDestination::Staked => json!("staked"),
+        RewardDestination::Stash => json!("stash"),
+        RewardDestination::Controller => json!("controller"),
+        RewardDestination::Account(account) => {
+            json!({ "account": account.to_ss58check_with_version(network) })
+        }
+    }
+}
+
+/// The JSON form of the nominator with controller `controller`.  The targets
+/// are stash addresses; their details are not included.
+async fn nominator_json(
+    controller: AccountId,
+    client: &Client<KusamaRuntime>,
+    network: Ss58AddressFormat,
+) -> Result<Value, Error> {
+    use substrate_subxt::staking::NominatorsStore;
+    let store = LedgerStore {
+        controller: controller.clone(),
+    };
+    let ledger = client
+        .fetch(&store, None)
+        .await?
+        .ok_or_else(|| format!("No nominator account found for controller {}", controller))?;
+    let stash = ledger.stash.clone();
+    let payee = client
+        .fetch(
+            &PayeeStore {
+                stash: stash.clone(),
+            },
+            None,
+        )
+        .await?
+        .ok_or_else(|| {
+            format!(
+                "No payee found for controller {} (this is a bug)",
+                controller
+            )
+        })?;
+    let nominations = client
+        .fetch(&NominatorsStore { stash }, None)
+        .await?
+        .map(|nominations| {
+            let targets: Vec<String> = nominations
+                .targets
+                .iter()
+                .map(|stash| stash.to_ss58check_with_version(network))
+                .collect();
+            json!({
+                "submitted_in": nominations.submitted_in,
+                "suppressed": nominations.suppressed,
+                "targets": targets,
+            })
+        });
+    Ok(json!({
+        "controller": controller.to_ss58check_with_version(network),
+        "ledger": ledger_json(&ledger, network),
+        "payee": payee_json(&payee, network),
+        "nominations": nominations,
+    }))
 }
 
 async fn display_nominators(
     controller: AccountId,
     client: &Client<KusamaRuntime>,
     network: Ss58AddressFormat,
+    format: OutputFormat,
 ) -> Result<(), Error> {
     use substrate_subxt::staking::{NominatorsStore, StakingLedger};
+    if format == OutputFormat::Json {
+        println!("{}", nominator_json(controller, client, network).await?);
+        return Ok(())
+    }
     let store = LedgerStore {
         controller: controller.clone(),
     };
@@ -88,24 +236,14 @@ async fn display_nominators(
                 controller
             )
         })?;
-    let mut props = client.properties().clone();
-    let mut good_symbol = true;
-    for i in props.token_symbol.bytes() {
-        good_symbol &= i.is_ascii_uppercase()
-    }
-    if !good_symbol {
-        props.token_symbol = "".to_owned()
-    }
-
     println!(
-        "Nominator account: {}\nStash balance: {} {sym}\nAmount at stake: {} {sym}\nAmount \
-         unlocking: {:?} {sym}\nPayee: {:?}",
+        "Nominator account: {}\nStash balance: {}\nAmount at stake: {}\nAmount unlocking: \
+         {}\nPayee: {:?}",
         stash.to_ss58check_with_version(network),
-        pad(props.token_decimals, total),
-        pad(props.token_decimals, active),
-        unlocking,
+        format_balance(total, network),
+        format_balance(active, network),
+        format_unlocking(&unlocking, network),
         payee,
-        sym = props.token_symbol,
     );
     let nominations = match client.fetch(&NominatorsStore { stash }, None).await? {
         None => {
@@ -123,7 +261,7 @@ async fn display_nominators(
             stash: stash.clone(),
         };
         if let Some(controller) = client.fetch(&bonded, None).await? {
-            crate::common::display_validators(client, &[controller], network).await?
+            crate::common::display_validators(client, &[controller], network, format).await?
         } else {
             println!(
                 "controller not found for stash {}\n",
@@ -138,6 +276,8 @@ pub(crate) async fn main<T: FnOnce() -> Result<super::HardStore, Error>>(
     cmd: Nominator,
     client: Pin<Box<dyn Future<Output = Result<Client<KusamaRuntime>, Error>>>>,
     network: Ss58AddressFormat,
+    format: OutputFormat,
+    wait: Wait,
     keystore: T,
 ) -> Result<Option<H256>, Error> {
     use std::convert::{TryFrom, TryInto};
@@ -145,13 +285,13 @@ pub(crate) async fn main<T: FnOnce() -> Result<super::HardStore, Error>>(
         Nominator::ShowAddress {
             address: (stash, provided_network),
         } => {
-            validate_network("", provided_network, network)?;
+            ledgeracio::validate_account_network(&stash, provided_network, network)?;
             let client = client.await?;
             let controller = match client.fetch(&BondedStore { stash }, None).await? {
                 Some(controller) => controller,
                 None => return Err("Controller not found for stash".to_owned().into()),
             };
-            display_nominators(controller, &client, network).await?;
+            display_nominators(controller, &client, network, format).await?;
             Ok(None)
         }
         Nominator::Show { index } => {
@@ -164,14 +304,19 @@ pub(crate) async fn main<T: FnOnce() -> Result<super::HardStore, Error>>(
             )
             .await?;
             for controller in nominators {
-                display_nominators(controller, &client, network).await?
+                display_nominators(controller, &client, network, format).await?
             }
             Ok(None)
         }
 
-        Nominator::Nominate { index, set } => {
+        Nominator::Nominate {
+            index,
+            set,
+            allowlist,
+            force,
+        } => {
             let path = LedgeracioPath::new(network, AccountType::Nominator, index)?;
-            let signer = keystore()?.signer(path).await?;
+            let keystore = keystore()?;
             if set.is_empty() {
                 return Err("Validator set cannot be empty".to_owned().into())
             }
@@ -189,20 +334,88 @@ pub(crate) async fn main<T: FnOnce() -> Result<super::HardStore, Error>>(
                 }
                 new_set.push(address)
             }
-            Ok(Some(client.await?.nominate(&signer, new_set).await?))
+            if let Some(allowlist) = allowlist {
+                let missing = not_allowed(&keystore, &allowlist, network, &new_set).await?;
+                if !missing.is_empty() {
+                    let missing = missing.join(", ");
+                    if !force {
+                        return Err(
+                            format!("Validators not in {}: {}", allowlist.display(), missing)
+                                .into(),
+                        )
+                    }
+                    eprintln!(
+                        "Warning: nominating validators not in {} because of --force: {}",
+                        allowlist.display(),
+                        missing
+                    );
+                }
+            }
+            let signer = keystore.signer(path).await?;
+            let client = client.await?;
+            let call = NominateCall { targets: new_set };
+            crate::common::submit(&client, call, &signer, wait).await
         }
         Nominator::Chill { index } => {
             let path = LedgeracioPath::new(network, AccountType::Nominator, index)?;
             let signer = keystore()?.signer(path).await?;
-            Ok(Some(client.await?.chill(&signer).await?))
+            let client = client.await?;
+            let call = ChillCall {
+                _runtime: PhantomData,
+            };
+            crate::common::submit(&client, call, &signer, wait).await
         }
         Nominator::SetPayee { index, target } => {
+            let target = super::check_payee(target, network)?;
             let path = LedgeracioPath::new(network, AccountType::Nominator, index)?;
             let signer = keystore()?.signer(path).await?;
-            Ok(Some(client.await?.set_payee(&signer, target).await?))
+            let client = client.await?;
+            let call = SetPayeeCall { payee: target };
+            crate::common::submit(&client, call, &signer, wait).await
+        }
+        Nominator::ChillThenUnbond { index, amount } => {
+            let amount = amount
+                .map(|amount| crate::common::parse_balance(&amount, network))
+                .transpose()?;
+            let path = LedgeracioPath::new(network, AccountType::Nominator, index)?;
+            let signer = keystore()?.signer(path).await?;
+            let client = client.await?;
+            crate::common::chill_then_unbond(&client, &signer, network, amount, wait).await
+        }
+        Nominator::BondExtra {
+            index,
+            amount,
+            keep_alive,
+        } => {
+            let path = LedgeracioPath::new(network, AccountType::Nominator, index)?;
+            let signer = keystore()?.signer(path).await?;
+            let client = client.await?;
+            crate::common::bond_extra(&client, &signer, network, &amount, &keep_alive, wait).await
+        }
+        Nominator::PayoutStakers {
+            index,
+            validator: (validator, provided_network),
+            depth,
+        } => {
+            ledgeracio::validate_account_network(&validator, provided_network, network)?;
+            let path = LedgeracioPath::new(network, AccountType::Nominator, index)?;
+            let signer = keystore()?.signer(path).await?;
+            let client = client.await?;
+            crate::common::payout_stakers(&client, &signer, validator, depth, wait).await
+        }
+        Nominator::WithdrawUnbonded {
+            index,
+            num_slashing_spans,
+        } => {
+            let path = LedgeracioPath::new(network, AccountType::Nominator, index)?;
+            let signer = keystore()?.signer(path).await?;
+            let client = client.await?;
+            crate::common::withdraw_unbonded(&client, &signer, num_slashing_spans, wait).await
         }
-        Nominator::Address { index } => {
-            crate::display_path(AccountType::Nominator, &keystore()?, network, index).await?;
+        Nominator::Address { index, on_device } => {
+            let keystore = keystore()?;
+            crate::display_path(AccountType::Nominator, &keystore, network, index, on_device)
+                .await?;
             Ok(None)
         }
     }
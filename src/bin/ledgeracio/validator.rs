@@ -19,16 +19,24 @@
 //! intention to validate etc. Requires a network connection.
 
 use super::{common::parse_ppb, parse_reward_destination, AccountType, AddressSource, Error,
-            LedgeracioPath, StructOpt};
+            LedgeracioPath, OutputFormat, StructOpt, Wait};
+use crate::calls::{session, Prefs, ValidatorsStore as PrefsStore};
 use codec::Decode;
 use core::{future::Future, pin::Pin};
+use futures::{stream, StreamExt, TryStreamExt};
 use ledgeracio::parse_address;
-use substrate_subxt::{session::SetKeysCallExt,
-                      sp_core::{crypto::{AccountId32 as AccountId, Ss58AddressFormat},
+use serde_json::json;
+use std::{collections::HashSet,
+          fs::File,
+          io::BufReader,
+          marker::PhantomData,
+          path::PathBuf};
+use substrate_subxt::{session::SetKeysCall,
+                      sp_core::{crypto::{AccountId32 as AccountId, Ss58AddressFormat, Ss58Codec},
                                 H256},
                       sp_runtime::Perbill,
-                      staking::{BondedStore, ChillCallExt, RewardDestination, SetPayeeCallExt,
-                                ValidateCallExt, ValidatorPrefs},
+                      staking::{BondedStore, ChillCall, SetPayeeCall, ValidateCall,
+                                ValidatorPrefs},
                       Client, KusamaRuntime, SessionKeys};
 
 #[derive(StructOpt, Debug)]
@@ -61,10 +69,52 @@ pub(crate) enum Validator {
     SetPayee {
         index: u32,
         #[structopt(parse(try_from_str = parse_reward_destination))]
-        target: RewardDestination<AccountId>,
+        target: super::Payee,
+    },
+    /// Chill, and then unbond some or all of the active stake
+    ChillThenUnbond {
+        index: u32,
+        /// The amount to unbond, in whole tokens.  By default, all of the
+        /// active stake is unbonded.
+        amount: Option<String>,
+    },
+    /// Bond more of the free balance of the stash
+    BondExtra {
+        index: u32,
+        /// The amount to bond, in whole tokens, or `max` for all of the free
+        /// balance except `--keep-alive`
+        amount: String,
+        /// With `max`, the amount to leave unbonded, in whole tokens
+        #[structopt(long, default_value = "1")]
+        keep_alive: String,
+    },
+    /// Pay out the unclaimed rewards of a validator, for every era in which it
+    /// earned points
+    PayoutStakers {
+        index: u32,
+        /// The stash of the validator
+        #[structopt(long, parse(try_from_str = parse_address))]
+        validator: (AccountId, u8),
+        /// Only pay out the last this many eras
+        #[structopt(long)]
+        depth: Option<u32>,
+    },
+    /// Withdraw the funds that have finished unbonding
+    WithdrawUnbonded {
+        index: u32,
+        /// The number of slashing spans of the stash.  By default, this is
+        /// read from the chain.
+        #[structopt(long)]
+        num_slashing_spans: Option<u32>,
     },
     /// Display the address of the given index
-    Address { index: u32 },
+    Address {
+        index: u32,
+        /// Also show the address on the Ledger screen, so that it can be
+        /// checked against the one printed.
+        #[structopt(long = "on-device")]
+        on_device: bool,
+    },
 }
 
 fn parse_keys(buffer: &str) -> Result<SessionKeys, Error> {
@@ -76,23 +126,172 @@ fn parse_keys(buffer: &str) -> Result<SessionKeys, Error> {
     Decode::decode(&mut &*bytes).map_err(|e| Box::new(e) as _)
 }
 
+/// Options for listing validators
+#[derive(StructOpt, Debug)]
+pub(crate) struct List {
+    /// Show whether each validator is in this signed allowlist
+    #[structopt(long)]
+    allowlist: Option<PathBuf>,
+    /// Sort by commission, lowest first, and validators without preferences
+    /// last
+    #[structopt(long)]
+    by_commission: bool,
+    /// Only list validators whose commission is at most this, as a decimal
+    /// between 0 and 1 inclusive
+    #[structopt(long, parse(try_from_str = parse_ppb))]
+    max_commission: Option<u32>,
+    /// Skip this many validators
+    #[structopt(long, default_value = "0")]
+    offset: usize,
+    /// List at most this many validators
+    #[structopt(long)]
+    limit: Option<usize>,
+}
+
+/// How many validator preferences `list` fetches at a time
+const CONCURRENT_FETCHES: usize = 8;
+
+/// List the validators of the current session with their commission, and
+/// whether they are blocking new nominations.  If an allowlist is given, also
+/// show whether each validator is in it.  The signature of the allowlist is
+/// not checked.
+///
+/// The offset and limit apply after filtering and sorting.  Without a filter
+/// or sort, only the preferences of the validators that are listed are
+/// fetched.  They are fetched [`CONCURRENT_FETCHES`] at a time.  Unless
+/// `quiet` is set, a progress bar tracks the preferences fetched.
+pub(crate) async fn list(
+    client: &Client<KusamaRuntime>,
+    network: Ss58AddressFormat,
+    format: OutputFormat,
+    quiet: bool,
+    options: List,
+) -> Result<(), Error> {
+    let List {
+        allowlist,
+        by_commission,
+        max_commission,
+        offset,
+        limit,
+    } = options;
+    let limit = limit.unwrap_or(usize::MAX);
+    let allowed: Option<HashSet<String>> = match allowlist {
+        Some(path) => {
+            let allowlist =
+                ledgeracio::allowlist::decode(BufReader::new(File::open(path)?), network)?;
+            Some(allowlist.addresses.into_iter().collect())
+        }
+        None => None,
+    };
+    let mut stashes = client
+        .fetch_or_default(
+            &session::ValidatorsStore {
+                _runtime: PhantomData,
+            },
+            None,
+        )
+        .await?;
+    let page_before_fetching = !by_commission && max_commission.is_none();
+    if page_before_fetching {
+        stashes = stashes.into_iter().skip(offset).take(limit).collect();
+    }
+    let progress = ledgeracio::progress_bar(stashes.len() as u64, quiet);
+    progress.set_message("Fetching validator preferences");
+    let prefs: Vec<_> = stream::iter(stashes.iter().map(|stash| async {
+        let prefs = client
+            .fetch(
+                &PrefsStore {
+                    stash: stash.clone(),
+                },
+                None,
+            )
+            .await;
+        progress.inc(1);
+        prefs
+    }))
+    .buffered(CONCURRENT_FETCHES)
+    .try_collect()
+    .await?;
+    progress.finish_and_clear();
+    let mut validators: Vec<(String, Option<Prefs>)> = stashes
+        .iter()
+        .map(|stash| stash.to_ss58check_with_version(network))
+        .zip(prefs)
+        .collect();
+    if let Some(max_commission) = max_commission {
+        validators.retain(|(_, prefs)| match prefs {
+            Some(prefs) => prefs.commission.deconstruct() <= max_commission,
+            None => false,
+        });
+    }
+    if by_commission {
+        // Validators without preferences sort last
+        validators.sort_by_key(|(_, prefs)| {
+            (prefs.is_none(), prefs.as_ref().map(|prefs| prefs.commission))
+        });
+    }
+    if !page_before_fetching {
+        validators = validators.into_iter().skip(offset).take(limit).collect();
+    }
+    for (stash, prefs) in validators {
+        let is_allowed = allowed.as_ref().map(|allowed| allowed.contains(&stash));
+        let commission = prefs.as_ref().map(|prefs| prefs.commission);
+        let blocked = prefs.and_then(|prefs| prefs.blocked);
+        match format {
+            OutputFormat::Json => println!(
+                "{}",
+                json!({
+                    "stash": stash,
+                    "commission_ppb": commission.map(Perbill::deconstruct),
+                    "blocked": blocked,
+                    "allowed": is_allowed,
+                })
+            ),
+            OutputFormat::Text => {
+                let commission = commission.map_or_else(
+                    || "no preferences".to_owned(),
+                    |commission| {
+                        format!(
+                            "{}%",
+                            crate::common::pad(7, commission.deconstruct().into())
+                        )
+                    },
+                );
+                let mut flags = vec![];
+                if blocked == Some(true) {
+                    flags.push("blocked")
+                }
+                match is_allowed {
+                    Some(true) => flags.push("allowed"),
+                    Some(false) => flags.push("not allowed"),
+                    None => {}
+                }
+                println!("{}\t{}\t{}", stash, commission, flags.join(", "))
+            }
+        }
+    }
+    Ok(())
+}
+
 pub(crate) async fn main<T: FnOnce() -> Result<super::HardStore, Error>>(
     cmd: Validator,
     client: Pin<Box<dyn Future<Output = Result<Client<KusamaRuntime>, Error>>>>,
     network: Ss58AddressFormat,
+    format: OutputFormat,
+    wait: Wait,
     keystore: T,
 ) -> Result<Option<H256>, Error> {
     match cmd {
         Validator::ShowAddress {
             address: (stash, provided_network),
         } => {
-            ledgeracio::validate_network("", provided_network, network)?;
+            ledgeracio::validate_account_network(&stash, provided_network, network)?;
             let client = client.await?;
             let controller = match client.fetch(&BondedStore { stash }, None).await? {
                 Some(controller) => controller,
                 None => return Err("Controller not found for stash".to_owned().into()),
             };
-            crate::common::display_validators(&client, &[controller], network).await?;
+            crate::common::display_validators(&client, &[controller], network, format).await?;
             Ok(None)
         }
         Validator::Announce { index, commission } => {
@@ -105,17 +304,31 @@ pub(crate) async fn main<T: FnOnce() -> Result<super::HardStore, Error>>(
                 commission: Perbill::from_parts(commission),
             };
             let signer = keystore()?.signer(path).await?;
-            Ok(Some(client.await?.validate(&signer, prefs).await?))
+            let client = client.await?;
+            let call = ValidateCall {
+                _runtime: PhantomData,
+                prefs,
+            };
+            crate::common::submit(&client, call, &signer, wait).await
         }
         Validator::Chill { index } => {
             let path = LedgeracioPath::new(network, AccountType::Validator, index)?;
             let signer = keystore()?.signer(path).await?;
-            Ok(Some(client.await?.chill(&signer).await?))
+            let client = client.await?;
+            let call = ChillCall {
+                _runtime: PhantomData,
+            };
+            crate::common::submit(&client, call, &signer, wait).await
         }
         Validator::ReplaceKey { index, keys } => {
             let path = LedgeracioPath::new(network, AccountType::Validator, index)?;
             let signer = keystore()?.signer(path).await?;
-            Ok(Some(client.await?.set_keys(&signer, keys, vec![]).await?))
+            let client = client.await?;
+            let call = SetKeysCall {
+                keys,
+                proof: vec![],
+            };
+            crate::common::submit(&client, call, &signer, wait).await
         }
         Validator::Show { index } => {
             let client = client.await?;
@@ -127,17 +340,60 @@ pub(crate) async fn main<T: FnOnce() -> Result<super::HardStore, Error>>(
                 AccountType::Validator,
             )
             .await?;
-            crate::common::display_validators(&client, &*validators, network).await?;
+            crate::common::display_validators(&client, &*validators, network, format).await?;
             Ok(None)
         }
         Validator::SetPayee { index, target } => {
+            let target = super::check_payee(target, network)?;
             let path = LedgeracioPath::new(network, AccountType::Validator, index)?;
             let signer = keystore()?.signer(path).await?;
-            client.await?.set_payee(&signer, target).await?;
-            Ok(None)
+            let client = client.await?;
+            let call = SetPayeeCall { payee: target };
+            crate::common::submit(&client, call, &signer, wait).await
+        }
+        Validator::ChillThenUnbond { index, amount } => {
+            let amount = amount
+                .map(|amount| crate::common::parse_balance(&amount, network))
+                .transpose()?;
+            let path = LedgeracioPath::new(network, AccountType::Validator, index)?;
+            let signer = keystore()?.signer(path).await?;
+            let client = client.await?;
+            crate::common::chill_then_unbond(&client, &signer, network, amount, wait).await
+        }
+        Validator::BondExtra {
+            index,
+            amount,
+            keep_alive,
+        } => {
+            let path = LedgeracioPath::new(network, AccountType::Validator, index)?;
+            let signer = keystore()?.signer(path).await?;
+            let client = client.await?;
+            crate::common::bond_extra(&client, &signer, network, &amount, &keep_alive, wait).await
+        }
+        Validator::PayoutStakers {
+            index,
+            validator: (validator, provided_network),
+            depth,
+        } => {
+            ledgeracio::validate_account_network(&validator, provided_network, network)?;
+            let path = LedgeracioPath::new(network, AccountType::Validator, index)?;
+            let signer = keystore()?.signer(path).await?;
+            let client = client.await?;
+            crate::common::payout_stakers(&client, &signer, validator, depth, wait).await
+        }
+        Validator::WithdrawUnbonded {
+            index,
+            num_slashing_spans,
+        } => {
+            let path = LedgeracioPath::new(network, AccountType::Validator, index)?;
+            let signer = keystore()?.signer(path).await?;
+            let client = client.await?;
+            crate::common::withdraw_unbonded(&client, &signer, num_slashing_spans, wait).await
         }
-        Validator::Address { index } => {
-            crate::display_path(AccountType::Validator, &keystore()?, network, index).await?;
+        Validator::Address { index, on_device } => {
+            let keystore = keystore()?;
+            crate::display_path(AccountType::Validator, &keystore, network, index, on_device)
+                .await?;
             Ok(None)
         }
     }
@@ -51,8 +51,8 @@ pub enum Error {
     /// Unsupported network (not Polkadot or Kusama)
     #[error("Unsupported network {0:?}")]
     UnsupportedNetwork(Ss58AddressFormat),
-    /// Index too large (greater than `2**31`)
-    #[error("Index too large: hardened child keys are not supported (greater than 2**31): {0}")]
+    /// Index too large (`2**31` or greater)
+    #[error("Index too large: hardened child keys are not supported (2**31 or greater): {0}")]
     UnsupportedKeyIndex(u32),
 }
 
@@ -71,14 +71,14 @@ const KUSAMA: u32 = 0x8000_01b2;
 
 impl LedgeracioPath {
     /// Create a new Ledgeracio derivation path, or return an error if the path
-    /// is not valid or if using a [hardened](https://en.bitcoin.it/wiki/BIP_0032#Extended_keys) key index (> 2**31).
+    /// is not valid or if using a [hardened](https://en.bitcoin.it/wiki/BIP_0032#Extended_keys) key index (>= 2**31).
     ///
     /// # Errors
     ///
     /// Returns `Err` if:
     ///
     /// - The network is not supported.
-    /// - The account index is greater than 2**31.
+    /// - The account index is 2**31 or greater.
     pub fn new(
         network: Ss58AddressFormat,
         account_type: AccountType,
@@ -89,7 +89,7 @@ impl LedgeracioPath {
             Ss58AddressFormat::KusamaAccount => KUSAMA,
             bad_network => return Err(Error::UnsupportedNetwork(bad_network)),
         };
-        if account_index > HARDENED {
+        if account_index >= HARDENED {
             return Err(Error::UnsupportedKeyIndex(account_index))
         }
         Ok(Self(BIP44Path([
@@ -106,8 +106,19 @@ impl Clone for LedgeracioPath {
     fn clone(&self) -> Self { Self(BIP44Path((self.0).0)) }
 }
 
+/// Formats the path in BIP32 notation, such as `m/44'/354'/0'/0'/1'`
 impl std::fmt::Display for LedgeracioPath {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> { self.0.fmt(f) }
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        f.write_str("m")?;
+        for &index in &(self.0).0 {
+            if index & HARDENED == 0 {
+                write!(f, "/{}", index)?
+            } else {
+                write!(f, "/{}'", index & !HARDENED)?
+            }
+        }
+        Ok(())
+    }
 }
 
 impl AsRef<[u32]> for LedgeracioPath {
@@ -117,3 +128,25 @@ impl AsRef<[u32]> for LedgeracioPath {
 impl AsRef<BIP44Path> for LedgeracioPath {
     fn as_ref(&self) -> &BIP44Path { &self.0 }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn displays_bip32_paths() {
+        let path = |network, account_type, index| {
+            LedgeracioPath::new(network, account_type, index)
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(
+            path(Ss58AddressFormat::PolkadotAccount, AccountType::Nominator, 1),
+            "m/44'/354'/0'/0'/1'"
+        );
+        assert_eq!(
+            path(Ss58AddressFormat::KusamaAccount, AccountType::Validator, 7),
+            "m/44'/434'/1'/0'/7'"
+        );
+    }
+}
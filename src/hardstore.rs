@@ -23,9 +23,15 @@
 use super::{Encode, Error, LedgeracioPath};
 use codec::Decode;
 use ledger_substrate::SubstrateApp;
-use std::{future::Future, pin::Pin, sync::Arc};
+use std::{future::Future,
+          io::Write,
+          path::{Path, PathBuf},
+          pin::Pin,
+          sync::{atomic::{AtomicBool, Ordering},
+                 Arc},
+          time::Duration};
 use substrate_subxt::{sp_core::crypto::{AccountId32 as AccountId, Ss58AddressFormat},
-                      sp_runtime::{generic::{SignedPayload, UncheckedExtrinsic},
+                      sp_runtime::{generic::{Era, SignedPayload, UncheckedExtrinsic},
                                    MultiSignature},
                       system::System,
                       Encoded, Runtime, SignedExtra};
@@ -33,6 +39,20 @@ use substrate_subxt::{sp_core::crypto::{AccountId32 as AccountId, Ss58AddressFor
 /// Hardware keystore
 pub struct HardStore {
     inner: Arc<SubstrateApp>,
+    timeout: Option<Duration>,
+    timed_out: AtomicBool,
+    audit_log: Option<PathBuf>,
+}
+
+/// Errors from [`HardStore`] that are not reported by the device itself
+#[derive(::thiserror::Error, Debug)]
+pub enum DeviceError {
+    /// The device did not respond in time
+    #[error("Timed out after {0:?} waiting for the Ledger device")]
+    Timeout(Duration),
+    /// A previous operation timed out, so the state of the transport is unknown
+    #[error("A previous operation on the Ledger device timed out; reconnect and try again")]
+    Unusable,
 }
 
 pub type Signed<T> = Pin<
@@ -70,8 +90,68 @@ impl HardStore {
         }(transport);
         Ok(Self {
             inner: Arc::new(app),
+            timeout: None,
+            timed_out: AtomicBool::new(false),
+            audit_log: None,
         })
     }
+
+    /// Fail device operations that take longer than `timeout`.  `None`, the
+    /// default, waits forever.  Operations that wait for the user to confirm
+    /// on the device are not affected: setting the public key, uploading an
+    /// allowlist, showing an address, and signing.
+    ///
+    /// Once an operation has timed out, a response may still be in flight, so
+    /// every later operation on this [`HardStore`] fails with
+    /// [`DeviceError::Unusable`].
+    #[must_use]
+    pub fn with_timeout(self, timeout: Option<Duration>) -> Self { Self { timeout, ..self } }
+
+    /// Append a line of JSON describing each signed extrinsic to the file at
+    /// `audit_log`.  If the line cannot be written, signing fails, so nothing
+    /// is submitted without being logged.
+    #[must_use]
+    pub fn with_audit_log(self, audit_log: Option<PathBuf>) -> Self {
+        Self { audit_log, ..self }
+    }
+
+    async fn run<T, E, F>(&self, future: F) -> Result<T, Error>
+    where
+        E: Into<Error>,
+        F: Future<Output = Result<T, E>>,
+    {
+        self.run_within(self.timeout, future).await
+    }
+
+    /// Run `future`, which waits for the user to confirm on the device, so
+    /// the timeout does not apply
+    async fn run_confirmed<T, E, F>(&self, future: F) -> Result<T, Error>
+    where
+        E: Into<Error>,
+        F: Future<Output = Result<T, E>>,
+    {
+        self.run_within(None, future).await
+    }
+
+    async fn run_within<T, E, F>(&self, timeout: Option<Duration>, future: F) -> Result<T, Error>
+    where
+        E: Into<Error>,
+        F: Future<Output = Result<T, E>>,
+    {
+        if self.timed_out.load(Ordering::SeqCst) {
+            return Err(DeviceError::Unusable.into())
+        }
+        match timeout {
+            None => future.await.map_err(Into::into),
+            Some(timeout) => match async_std::future::timeout(timeout, future).await {
+                Ok(result) => result.map_err(Into::into),
+                Err(_) => {
+                    self.timed_out.store(true, Ordering::SeqCst);
+                    Err(DeviceError::Timeout(timeout).into())
+                }
+            },
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -79,6 +159,7 @@ pub struct HardSigner {
     app: Arc<SubstrateApp>,
     path: LedgeracioPath,
     address: AccountId,
+    audit_log: Option<PathBuf>,
 }
 
 impl HardStore {
@@ -92,8 +173,48 @@ impl HardStore {
     /// to the correct app, or if there is an error communicating with the
     /// Ledger device.
     pub async fn signer(&self, path: LedgeracioPath) -> Result<HardSigner, Error> {
+        let address = self.account_id(&path).await?;
         let app = self.inner.clone();
-        let ledger_address = app.get_address(path.as_ref(), false).await;
+        let audit_log = self.audit_log.clone();
+        Ok(HardSigner {
+            app,
+            path,
+            address,
+            audit_log,
+        })
+    }
+
+    /// Get the account ID for `path`, without building a [`HardSigner`].  The
+    /// address is not displayed on the device, so no confirmation is needed.
+    ///
+    /// # Errors
+    ///
+    /// This function will fail if no Ledger is inserted, the Ledger is not open
+    /// to the correct app, or if there is an error communicating with the
+    /// Ledger device.
+    pub async fn account_id(&self, path: &LedgeracioPath) -> Result<AccountId, Error> {
+        self.get_address(path, false).await
+    }
+
+    /// Get the account ID for `path`, and show its address on the device for
+    /// the user to check.  Nothing is signed.
+    ///
+    /// # Errors
+    ///
+    /// This function will fail if no Ledger is inserted, the Ledger is not open
+    /// to the correct app, the user rejects the address, or if there is an
+    /// error communicating with the Ledger device.
+    pub async fn show_address(&self, path: &LedgeracioPath) -> Result<AccountId, Error> {
+        self.get_address(path, true).await
+    }
+
+    async fn get_address(&self, path: &LedgeracioPath, show: bool) -> Result<AccountId, Error> {
+        let future = self.inner.get_address(path.as_ref(), show);
+        let ledger_address = if show {
+            self.run_confirmed(future).await
+        } else {
+            self.run(future).await
+        };
 
         let ledger_address = match ledger_address {
             Ok(e) => e,
@@ -104,11 +225,10 @@ impl HardStore {
                      are using.",
                     path, e
                 );
-                return Err(Box::new(e) as _)
+                return Err(e)
             }
         };
-        let address = ledger_address.public_key.into();
-        Ok(HardSigner { app, path, address })
+        Ok(ledger_address.public_key.into())
     }
 
     /// Set a public key
@@ -118,10 +238,7 @@ impl HardStore {
     /// This function will fail if the device refuses the operation, the app is
     /// not the special Ledgeracio app, or an I/O error occurs.
     pub async fn set_pubkey(&self, key: &'_ [u8; 32]) -> Result<(), Error> {
-        self.inner
-            .allowlist_set_pubkey(key)
-            .await
-            .map_err(From::from)
+        self.run_confirmed(self.inner.allowlist_set_pubkey(key)).await
     }
 
     /// Set a public key
@@ -131,10 +248,7 @@ impl HardStore {
     /// This function will fail if the device refuses the operation, the app is
     /// not the special Ledgeracio app, or an I/O error occurs.
     pub async fn allowlist_upload(&self, allowlist: &[u8]) -> Result<(), Error> {
-        self.inner
-            .allowlist_upload(allowlist)
-            .await
-            .map_err(From::from)
+        self.run_confirmed(self.inner.allowlist_upload(allowlist)).await
     }
 
     /// Get the public key
@@ -143,7 +257,18 @@ impl HardStore {
     ///
     /// This function will fail if an I/O error occurs.
     pub async fn get_pubkey(&self) -> Result<[u8; 32], Error> {
-        self.inner.allowlist_get_pubkey().await.map_err(From::from)
+        self.run(self.inner.allowlist_get_pubkey()).await
+    }
+
+    /// Get the version of the app running on the device, as `(major, minor,
+    /// patch)`.
+    ///
+    /// # Errors
+    ///
+    /// This function will fail if an I/O error occurs.
+    pub async fn app_version(&self) -> Result<(u16, u16, u16), Error> {
+        let version = self.run(self.inner.get_version()).await?;
+        Ok((version.major, version.minor, version.patch))
     }
 }
 
@@ -182,15 +307,65 @@ impl HardSigner {
             Ok(e) => e,
             Err(e) => return Err(e.to_string()),
         };
-        Ok(UncheckedExtrinsic::new_signed(
+        let call_data = call.encode();
+        let extra_data = extra.encode();
+        let extrinsic = UncheckedExtrinsic::new_signed(
             call,
             self.address.clone(),
             signature,
             extra,
-        ))
+        );
+        if let Some(audit_log) = &self.audit_log {
+            let entry = audit_entry(&self.address, &call_data, &extra_data, &extrinsic.encode());
+            append_line(audit_log, &entry.to_string()).map_err(|e| {
+                format!("Writing to audit log {} failed: {}", audit_log.display(), e)
+            })?
+        }
+        Ok(extrinsic)
     }
 }
 
+/// Describe a signed extrinsic for the audit log.  `extra` is the encoded
+/// signed extension data: with the default extensions, this is the era
+/// followed by the compact-encoded nonce.
+fn audit_entry(
+    account: &AccountId,
+    call: &[u8],
+    extra: &[u8],
+    extrinsic: &[u8],
+) -> serde_json::Value {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let mut extra = extra;
+    let nonce = Era::decode(&mut extra)
+        .and_then(|_| codec::Compact::<u32>::decode(&mut extra))
+        .ok()
+        .map(|nonce| nonce.0);
+    let hash = blake2b_simd::Params::new().hash_length(32).hash(extrinsic);
+    serde_json::json!({
+        "timestamp": timestamp,
+        "account_id": format!("0x{}", hex::encode(account)),
+        "pallet_index": call.get(0),
+        "call_index": call.get(1),
+        "call": format!("0x{}", hex::encode(call)),
+        "nonce": nonce,
+        "extrinsic_hash": format!("0x{}", hash.to_hex()),
+    })
+}
+
+/// Append `line` and a newline to the file at `path` with a single write, so
+/// that concurrent writers do not interleave
+fn append_line(path: &Path, line: &str) -> std::io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(format!("{}\n", line).as_bytes())
+}
+
 type T = substrate_subxt::KusamaRuntime;
 
 impl substrate_subxt::Signer<substrate_subxt::KusamaRuntime> for HardSigner {
@@ -206,3 +381,21 @@ impl substrate_subxt::Signer<substrate_subxt::KusamaRuntime> for HardSigner {
         Box::pin(async move { tmp.sign::<T>(extrinsic).await })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audit_entry_decodes_the_nonce() {
+        let account = AccountId::from([1; 32]);
+        // An immortal era, nonce 5, and no tip
+        let entry = audit_entry(&account, &[6, 0, 42], &[0, 5 << 2, 0], b"extrinsic");
+        assert_eq!(entry["nonce"], 5);
+        assert_eq!(entry["pallet_index"], 6);
+        assert_eq!(entry["call_index"], 0);
+        assert_eq!(entry["call"], "0x06002a");
+        assert_eq!(entry["account_id"], format!("0x{}", "01".repeat(32)));
+        assert!(entry["extrinsic_hash"].as_str().unwrap().starts_with("0x"));
+    }
+}
@@ -14,18 +14,20 @@
 // You should have received a copy of the GNU General Public License
 // along with ledgeracio.  If not, see <http://www.gnu.org/licenses/>.
 
-//! Ledgeracio utility library.  Do not depend on this.
+//! Ledgeracio utility library.  Apart from the [`allowlist`] module, which
+//! compiles and verifies signed allowlists, do not depend on this.
 
 #![deny(clippy::all, clippy::pedantic)]
 #![allow(clippy::non_ascii_literal)]
 #![forbid(unsafe_code)]
 
+pub mod allowlist;
 mod derivation;
 mod hardstore;
 
 use codec::Encode;
-pub use derivation::{AccountType, LedgeracioPath};
-pub use hardstore::{HardSigner, HardStore};
+pub use derivation::{AccountType, Error as DerivationError, LedgeracioPath};
+pub use hardstore::{DeviceError, HardSigner, HardStore};
 
 #[cfg(not(unix))]
 compile_error!("Only *nix-like platforms are supported");
@@ -45,6 +47,56 @@ pub type Error = Box<dyn std::error::Error + Send + Sync>;
 
 // pub type Runtime = substrate_subxt::KusamaRuntime;
 
+/// The format of data printed by a command
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human-readable text
+    Text,
+    /// JSON
+    Json,
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err("Output format must be `text` or `json`"),
+        }
+    }
+}
+
+/// Run `command`, failing if it takes longer than `deadline`.  An unfinished
+/// command is dropped before this returns, which closes its connection to the
+/// node and releases the Ledger device.
+pub async fn with_deadline<F: Future<Output = Result<(), Error>>>(
+    command: F,
+    deadline: Option<std::time::Duration>,
+) -> Result<(), Error> {
+    match deadline {
+        None => command.await,
+        Some(deadline) => async_std::future::timeout(deadline, command)
+            .await
+            .unwrap_or_else(|_| Err(format!("Timed out after {:?}", deadline).into())),
+    }
+}
+
+/// Create a progress bar on stderr with `len` steps.  The bar is hidden if
+/// `quiet` is set, and indicatif also hides it when stderr is not a terminal.
+#[must_use]
+pub fn progress_bar(len: u64, quiet: bool) -> indicatif::ProgressBar {
+    if quiet {
+        return indicatif::ProgressBar::hidden()
+    }
+    let bar = indicatif::ProgressBar::new(len);
+    bar.set_style(
+        indicatif::ProgressStyle::default_bar().template("{msg} [{bar:40}] {pos}/{len}"),
+    );
+    bar
+}
+
 /// Parse an SS58 address
 ///
 /// # Errors
@@ -56,35 +108,114 @@ pub fn parse_address<T: Ss58Codec>(arg: &str) -> Result<(T, u8), String> {
         .map(|(x, y)| (x, y.into()))
 }
 
+/// Describe the network with SS58 prefix `prefix`, including the prefix
+/// itself.
+fn describe_network(prefix: u8) -> String {
+    match Ss58AddressFormat::try_from(prefix) {
+        Ok(format) => format!("{} (prefix {})", String::from(format), prefix),
+        Err(()) => format!("unknown (prefix {})", prefix),
+    }
+}
+
 /// Validate that the address `address`, which parsed to network
 /// `provided_network`, is valid for network `network`.
 ///
 /// # Errors
 ///
-/// Fails if the address was for the wrong network.
+/// Fails if the address was for the wrong network.  The error names both
+/// networks and their prefixes.
 pub fn validate_network(
     address: &str,
     provided_network: u8,
     network: Ss58AddressFormat,
 ) -> Result<(), Error> {
-    if network == provided_network.try_into().unwrap() {
+    if u8::from(network) == provided_network {
         Ok(())
     } else {
         Err(format!(
             "Network mismatch: address {} is for network {}, but you asked to use network {}",
             address,
-            String::from(Ss58AddressFormat::try_from(provided_network).unwrap()),
-            String::from(network),
+            describe_network(provided_network),
+            describe_network(network.into()),
         )
         .into())
     }
 }
 
-/// Converts a network name into an address format.
+/// Validate that `account`, which was parsed from an address for network
+/// `provided_network`, is valid for network `network`.
 ///
 /// # Errors
 ///
-/// Fails if `Ss58AddressFormat::try_from` fails.
+/// As for [`validate_network`], with `account` written as an address for
+/// `provided_network`.
+pub fn validate_account_network<T: Ss58Codec>(
+    account: &T,
+    provided_network: u8,
+    network: Ss58AddressFormat,
+) -> Result<(), Error> {
+    let format = Ss58AddressFormat::try_from(provided_network)
+        .unwrap_or_else(|()| Ss58AddressFormat::Custom(provided_network));
+    validate_network(
+        &account.to_ss58check_with_version(format),
+        provided_network,
+        network,
+    )
+}
+
+/// Converts a network name into an address format.  `westend` is accepted as
+/// an alias for the generic Substrate format, which Westend uses.  `dot`,
+/// `ksm`, and `wnd` are accepted as short names for Polkadot, Kusama, and
+/// Westend, and a decimal number is accepted as an SS58 prefix.
+///
+/// # Errors
+///
+/// Fails if the name is not a known network or a prefix from 0 to 255.
 pub fn get_network(address: &str) -> Result<Ss58AddressFormat, Error> {
-    Ss58AddressFormat::try_from(address).map_err(|_| format!("Unknown network {}", address).into())
+    if let Ok(prefix) = address.parse::<u8>() {
+        return Ok(Ss58AddressFormat::try_from(prefix)
+            .unwrap_or_else(|()| Ss58AddressFormat::Custom(prefix)))
+    }
+    match address {
+        "westend" | "wnd" => Ok(Ss58AddressFormat::SubstrateAccount),
+        "dot" => Ok(Ss58AddressFormat::PolkadotAccount),
+        "ksm" => Ok(Ss58AddressFormat::KusamaAccount),
+        _ => Ss58AddressFormat::try_from(address).map_err(|_| {
+            format!(
+                "Unknown network {}: expected polkadot (dot), kusama (ksm), westend (wnd), \
+                 another network name, or an SS58 prefix from 0 to 255",
+                address
+            )
+            .into()
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn network_names_and_prefixes() {
+        let network = |name| get_network(name).unwrap();
+        assert_eq!(network("kusama"), Ss58AddressFormat::KusamaAccount);
+        assert_eq!(network("ksm"), Ss58AddressFormat::KusamaAccount);
+        assert_eq!(network("dot"), Ss58AddressFormat::PolkadotAccount);
+        assert_eq!(network("wnd"), Ss58AddressFormat::SubstrateAccount);
+        assert_eq!(network("2"), Ss58AddressFormat::KusamaAccount);
+        assert_eq!(network("42"), Ss58AddressFormat::SubstrateAccount);
+        assert_eq!(network("200"), Ss58AddressFormat::Custom(200));
+        assert!(get_network("256").is_err());
+        assert!(get_network("nonsense").is_err());
+    }
+
+    #[test]
+    fn mismatch_names_the_address() {
+        let account = AccountId::from([1; 32]);
+        let address = account.to_ss58check_with_version(Ss58AddressFormat::KusamaAccount);
+        let polkadot = Ss58AddressFormat::PolkadotAccount;
+        let error = validate_account_network(&account, 2, polkadot).unwrap_err();
+        assert!(error.to_string().contains(&address));
+        assert!(validate_account_network(&account, 0, polkadot).is_ok());
+    }
 }
@@ -0,0 +1,63 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of ledgeracio.
+//
+// ledgeracio is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ledgeracio is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ledgeracio.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Ctrl-C must end `ledgeracio-allowlist` even while it is blocked outside the
+//! executor, here reading a secret key from a pipe that is never closed.
+
+use std::{os::unix::process::ExitStatusExt,
+          process::{Command, Stdio},
+          thread::sleep,
+          time::{Duration, Instant}};
+
+#[test]
+fn interrupt_during_blocking_read() {
+    let dir = std::env::temp_dir().join(format!("ledgeracio-interrupt-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let allowlist = dir.join("allowlist.txt");
+    std::fs::write(&allowlist, "").unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ledgeracio-allowlist"))
+        .args(&["--network", "polkadot", "sign"])
+        .args(&["--secret-fd", "0", "--nonce", "1"])
+        .arg("--file")
+        .arg(&allowlist)
+        .arg("--output")
+        .arg(dir.join("allowlist.signed"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    // Give it time to block on reading the secret key
+    sleep(Duration::from_secs(1));
+    let status = Command::new("kill")
+        .args(&["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status
+        }
+        if start.elapsed() > Duration::from_secs(10) {
+            child.kill().unwrap();
+            panic!("ledgeracio-allowlist ignored Ctrl-C")
+        }
+        sleep(Duration::from_millis(50))
+    };
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_eq!(status.signal(), Some(2));
+}
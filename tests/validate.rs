@@ -0,0 +1,42 @@
+// Copyright 2020 Parity Technologies (UK) Ltd.
+// This file is part of ledgeracio.
+//
+// ledgeracio is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// ledgeracio is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with ledgeracio.  If not, see <http://www.gnu.org/licenses/>.
+
+//! `ledgeracio-allowlist validate` must not require `--network`.
+
+use std::process::Command;
+
+#[test]
+fn validate_without_network() {
+    let dir = std::env::temp_dir().join(format!("ledgeracio-validate-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let allowlist = dir.join("allowlist.signed");
+    // Nonce 7, no addresses, and a signature that `validate` does not check
+    let mut contents = vec![7, 0, 0, 0, 0, 0, 0, 0];
+    contents.extend_from_slice(&[0; 64]);
+    std::fs::write(&allowlist, contents).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_ledgeracio-allowlist"))
+        .arg("validate")
+        .arg(&allowlist)
+        .output()
+        .unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(output.stdout, b"Valid empty allowlist with nonce 7\n");
+}